@@ -0,0 +1,398 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! What to do when the battery gets to a level, after a countdown that plugging in the charger
+//! cancels
+
+use std::fmt;
+use std::io;
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use osd::config::Config;
+use thiserror::Error;
+use zbus::blocking::Connection;
+use zbus::proxy;
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1",
+    gen_async = false,
+    blocking_name = "LoginManagerProxy"
+)]
+trait LoginManager {
+    fn suspend(&self, interactive: bool) -> zbus::Result<()>;
+    fn hibernate(&self, interactive: bool) -> zbus::Result<()>;
+    fn hybrid_sleep(&self, interactive: bool) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Action {
+    #[default]
+    Nothing,
+    /// Run `command` with `sh -c`
+    Command,
+    Suspend,
+    Hibernate,
+    HybridSleep,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Action::Nothing),
+            "command" => Ok(Action::Command),
+            "suspend" => Ok(Action::Suspend),
+            "hibernate" => Ok(Action::Hibernate),
+            "hybrid-sleep" => Ok(Action::HybridSleep),
+            _ => Err(format!(
+                "Unknown critical action {}, must be none, command, suspend, hibernate or hybrid-sleep",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Action::Nothing => "none",
+            Action::Command => "command",
+            Action::Suspend => "suspend",
+            Action::Hibernate => "hibernate",
+            Action::HybridSleep => "hybrid-sleep",
+        })
+    }
+}
+
+impl Action {
+    /// What the countdown OSD says is about to happen
+    pub fn describe(self) -> &'static str {
+        match self {
+            Action::Nothing => "Nothing",
+            Action::Command => "Running the critical battery command",
+            Action::Suspend => "Suspending",
+            Action::Hibernate => "Hibernating",
+            Action::HybridSleep => "Entering hybrid sleep",
+        }
+    }
+
+    /// Whether the system goes to sleep, and the battery may still be low when it wakes up
+    pub fn sleeps(self) -> bool {
+        matches!(
+            self,
+            Action::Suspend | Action::Hibernate | Action::HybridSleep
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ActionError {
+    #[error("Failed to run {0:?}: {1}")]
+    Command(String, io::Error),
+    #[error("No command to run is configured")]
+    NoCommand,
+    #[error("Failed to ask logind to {0}: {1}")]
+    Logind(Action, zbus::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CriticalAction {
+    pub action: Action,
+    pub command: String,
+    /// How long the countdown lasts
+    pub grace_period: Duration,
+}
+
+impl CriticalAction {
+    /// Load the action of the level `level`
+    pub fn load(config: &mut Config, level: &str) -> CriticalAction {
+        CriticalAction {
+            action: config.get_default(level, "action", Action::Nothing),
+            command: config.get_default(level, "command", String::new()),
+            grace_period: Duration::from_secs(config.get_default(level, "grace period", 60)),
+        }
+    }
+
+    /// Perform the action, asking logind on the system bus
+    pub fn perform(&self) -> Result<(), ActionError> {
+        match self.action {
+            Action::Nothing => Ok(()),
+            Action::Command => self.run_command(),
+            action => {
+                let connection =
+                    Connection::system().map_err(|err| ActionError::Logind(action, err))?;
+                self.perform_on(&connection)
+            }
+        }
+    }
+
+    /// Perform the action, asking logind on `connection`
+    pub fn perform_on(&self, connection: &Connection) -> Result<(), ActionError> {
+        let logind = |action: Action| -> Result<(), ActionError> {
+            let error = |err| ActionError::Logind(action, err);
+            let manager = LoginManagerProxy::new(connection).map_err(error)?;
+            match action {
+                Action::Suspend => manager.suspend(false),
+                Action::Hibernate => manager.hibernate(false),
+                Action::HybridSleep => manager.hybrid_sleep(false),
+                Action::Nothing | Action::Command => Ok(()),
+            }
+            .map_err(error)
+        };
+        match self.action {
+            Action::Nothing => Ok(()),
+            Action::Command => self.run_command(),
+            action => logind(action),
+        }
+    }
+
+    fn run_command(&self) -> Result<(), ActionError> {
+        if self.command.is_empty() {
+            return Err(ActionError::NoCommand);
+        }
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .spawn()
+            .map_err(|err| ActionError::Command(self.command.clone(), err))?;
+        let command = self.command.clone();
+        // Reap the child without holding up the daemon
+        thread::spawn(move || match child.wait() {
+            Ok(status) if !status.success() => warn!("{:?} exited with {}", command, status),
+            Ok(_) => {}
+            Err(err) => warn!("Failed to wait for {:?}: {}", command, err),
+        });
+        Ok(())
+    }
+}
+
+/// What the daemon has to do after an [`Escalation::step`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Idle,
+    /// A countdown is running, with this much time left
+    Countdown(Duration),
+    /// The battery is no longer at a level with an action, the countdown is over
+    Cancel,
+    /// The countdown has run out, perform the action
+    Perform,
+}
+
+/// Where the battery is on the way to the critical action
+#[derive(Debug, Clone, PartialEq)]
+pub enum Escalation {
+    Idle,
+    Countdown(Instant, CriticalAction),
+    /// The action has been performed at this point. A command isn't run again before the battery
+    /// recovers, but the system is put to sleep again if it is still low a grace period later,
+    /// which is usually after a resume.
+    Done(Instant, CriticalAction),
+}
+
+impl Escalation {
+    /// Move on, given the action of the level the battery is at, if it has one; a level with
+    /// another action starts the countdown over
+    pub fn step(&mut self, action: Option<&CriticalAction>, now: Instant) -> Step {
+        match (&*self, action) {
+            (Escalation::Idle, Some(action)) => self.count_down(action, now),
+            (Escalation::Countdown(_, current) | Escalation::Done(_, current), Some(action))
+                if current != action =>
+            {
+                self.count_down(action, now)
+            }
+            (Escalation::Countdown(deadline, _), Some(_)) if *deadline > now => {
+                Step::Countdown(*deadline - now)
+            }
+            (Escalation::Countdown(_, _), Some(action)) => {
+                *self = Escalation::Done(now, action.clone());
+                Step::Perform
+            }
+            (Escalation::Countdown(_, _), None) => {
+                *self = Escalation::Idle;
+                Step::Cancel
+            }
+            (Escalation::Done(performed, _), Some(action))
+                if action.action.sleeps() && now >= *performed + action.grace_period =>
+            {
+                self.count_down(action, now)
+            }
+            (Escalation::Done(_, _), Some(_)) => Step::Idle,
+            (Escalation::Idle, None) | (Escalation::Done(_, _), None) => {
+                *self = Escalation::Idle;
+                Step::Idle
+            }
+        }
+    }
+
+    fn count_down(&mut self, action: &CriticalAction, now: Instant) -> Step {
+        *self = Escalation::Countdown(now + action.grace_period, action.clone());
+        Step::Countdown(action.grace_period)
+    }
+}
+
+#[cfg(test)]
+mod action_tests {
+    use super::*;
+    use simple_osd_test_support::Bus;
+    use std::sync::{Arc, Mutex};
+    use zbus::interface;
+
+    fn settings(action: Action) -> CriticalAction {
+        CriticalAction {
+            action,
+            command: String::new(),
+            grace_period: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn counts_down_then_performs_once() {
+        let settings = settings(Action::Suspend);
+        let action = Some(&settings);
+        let start = Instant::now();
+        let mut escalation = Escalation::Idle;
+        assert_eq!(
+            escalation.step(action, start),
+            Step::Countdown(Duration::from_secs(60))
+        );
+        assert_eq!(
+            escalation.step(action, start + Duration::from_secs(45)),
+            Step::Countdown(Duration::from_secs(15))
+        );
+        assert_eq!(
+            escalation.step(action, start + Duration::from_secs(60)),
+            Step::Perform
+        );
+        assert_eq!(
+            escalation.step(action, start + Duration::from_secs(70)),
+            Step::Idle
+        );
+    }
+
+    #[test]
+    fn sleeps_again_if_still_low() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        for (action, rearmed) in [(Action::HybridSleep, true), (Action::Command, false)] {
+            let settings = settings(action);
+            let mut escalation = Escalation::Idle;
+            escalation.step(Some(&settings), start);
+            assert_eq!(escalation.step(Some(&settings), at(60)), Step::Perform);
+            // Resumed with the charger still unplugged
+            let expected = if rearmed {
+                Step::Countdown(Duration::from_secs(60))
+            } else {
+                Step::Idle
+            };
+            assert_eq!(escalation.step(Some(&settings), at(120)), expected);
+        }
+    }
+
+    #[test]
+    fn plugging_in_cancels() {
+        let settings = settings(Action::Hibernate);
+        let start = Instant::now();
+        let mut escalation = Escalation::Idle;
+        escalation.step(Some(&settings), start);
+        assert_eq!(
+            escalation.step(None, start + Duration::from_secs(10)),
+            Step::Cancel
+        );
+        assert_eq!(
+            escalation.step(Some(&settings), start + Duration::from_secs(20)),
+            Step::Countdown(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn another_action_starts_over() {
+        let command = settings(Action::Command);
+        let suspend = settings(Action::Suspend);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut escalation = Escalation::Idle;
+        escalation.step(Some(&command), start);
+        // Down to the next level while the command is still counted down
+        assert_eq!(
+            escalation.step(Some(&suspend), at(30)),
+            Step::Countdown(Duration::from_secs(60))
+        );
+        assert_eq!(escalation.step(Some(&suspend), at(90)), Step::Perform);
+
+        let mut escalation = Escalation::Idle;
+        escalation.step(Some(&command), start);
+        assert_eq!(escalation.step(Some(&command), at(60)), Step::Perform);
+        // The command has been run, but the battery keeps going down
+        assert_eq!(
+            escalation.step(Some(&suspend), at(70)),
+            Step::Countdown(Duration::from_secs(60))
+        );
+        assert_eq!(escalation.step(Some(&suspend), at(130)), Step::Perform);
+    }
+
+    #[test]
+    fn parses_actions() {
+        assert_eq!("hybrid-sleep".parse(), Ok(Action::HybridSleep));
+        assert!("reboot".parse::<Action>().is_err());
+    }
+
+    #[derive(Default)]
+    struct MockLogind {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.freedesktop.login1.Manager")]
+    impl MockLogind {
+        fn suspend(&self, interactive: bool) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("Suspend({})", interactive));
+        }
+        fn hibernate(&self, interactive: bool) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("Hibernate({})", interactive));
+        }
+    }
+
+    #[test]
+    fn asks_logind() {
+        let bus = Bus::start();
+        let logind = MockLogind::default();
+        let calls = logind.calls.clone();
+        let _service = bus.serve("org.freedesktop.login1", "/org/freedesktop/login1", logind);
+
+        settings(Action::Hibernate)
+            .perform_on(&bus.connect())
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![String::from("Hibernate(false)")]
+        );
+    }
+
+    #[test]
+    fn runs_command() {
+        let path =
+            std::env::temp_dir().join(format!("simple-osd-battery-action-{}", std::process::id()));
+        let settings = CriticalAction {
+            command: format!("touch {}", path.display()),
+            ..settings(Action::Command)
+        };
+        settings.perform().unwrap();
+        let started = Instant::now();
+        while !path.exists() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(path.exists());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,254 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Time-to-empty estimation of our own, since the one of the kernel is often missing or jumps
+//! around: either the kernel's value smoothed over time, or a linear regression of the charge
+//! (energy, where the battery tells) over the last few minutes
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How far back the regression looks
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+/// How long the samples of the regression have to span before it can be trusted
+const MIN_SPAN: Duration = Duration::from_secs(2 * 60);
+/// Time constant of the smoothing of the kernel's estimation
+const TIME_CONSTANT: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeEstimation {
+    /// The kernel's estimation smoothed, or our own if there is none
+    Auto,
+    /// The kernel's estimation as it is, or our own if there is none
+    Kernel,
+    /// Our own estimation only
+    Own,
+}
+
+impl FromStr for TimeEstimation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(TimeEstimation::Auto),
+            "kernel" => Ok(TimeEstimation::Kernel),
+            "own" => Ok(TimeEstimation::Own),
+            _ => Err(format!(
+                "Unknown time estimation {}, must be auto, kernel or own",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TimeEstimation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TimeEstimation::Auto => "auto",
+            TimeEstimation::Kernel => "kernel",
+            TimeEstimation::Own => "own",
+        })
+    }
+}
+
+/// Estimates the time-to-empty of one battery from the readings it is fed
+#[derive(Debug, Clone, Default)]
+pub struct Estimator {
+    /// When, and at what charge, while discharging
+    samples: VecDeque<(Instant, f32)>,
+    /// The smoothed kernel estimation, and when it was last updated
+    smoothed: Option<(Instant, f32)>,
+}
+
+impl Estimator {
+    /// Take a reading of the `charge` left (in any unit) at `now` and estimate the time-to-empty
+    /// in seconds, given that of the kernel
+    pub fn update(
+        &mut self,
+        mode: TimeEstimation,
+        now: Instant,
+        charge: f32,
+        charging: bool,
+        kernel: Option<f32>,
+    ) -> Option<f32> {
+        if charging {
+            *self = Estimator::default();
+            return None;
+        }
+
+        self.samples.push_back((now, charge));
+        while self
+            .samples
+            .front()
+            .is_some_and(|&(time, _)| now - time > WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let kernel = kernel.filter(|t| t.is_finite() && *t > 0.);
+        self.smoothed = kernel.map(|kernel| match self.smoothed {
+            Some((last, smoothed)) => {
+                let elapsed = (now - last).as_secs_f32();
+                // Where the smoothed estimation would be by now had nothing changed
+                let predicted = (smoothed - elapsed).max(0.);
+                let alpha = 1. - (-elapsed / TIME_CONSTANT.as_secs_f32()).exp();
+                (now, predicted + alpha * (kernel - predicted))
+            }
+            None => (now, kernel),
+        });
+
+        match mode {
+            TimeEstimation::Auto => self.smoothed.map(|(_, t)| t).or_else(|| self.regression()),
+            TimeEstimation::Kernel => kernel.or_else(|| self.regression()),
+            TimeEstimation::Own => self.regression(),
+        }
+    }
+
+    /// Time until the charge line fitted through the samples hits zero
+    fn regression(&self) -> Option<f32> {
+        let &(first, _) = self.samples.front()?;
+        let &(last, _) = self.samples.back()?;
+        if last - first < MIN_SPAN {
+            return None;
+        }
+
+        let points: Vec<(f32, f32)> = self
+            .samples
+            .iter()
+            .map(|&(time, charge)| ((time - first).as_secs_f32(), charge))
+            .collect();
+        let n = points.len() as f32;
+        let mean_t = points.iter().map(|p| p.0).sum::<f32>() / n;
+        let mean_charge = points.iter().map(|p| p.1).sum::<f32>() / n;
+        let covariance: f32 = points
+            .iter()
+            .map(|(t, charge)| (t - mean_t) * (charge - mean_charge))
+            .sum();
+        let variance: f32 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+        // In charge per second
+        let slope = covariance / variance;
+        if !slope.is_finite() || slope >= 0. {
+            return None;
+        }
+
+        let now = (last - first).as_secs_f32();
+        let charge = mean_charge + slope * (now - mean_t);
+        Some((charge / -slope).max(0.))
+    }
+}
+
+#[cfg(test)]
+mod estimate_tests {
+    use super::*;
+
+    /// Feed `charge(t)` every 10 seconds for `minutes`, returning the last estimation
+    fn feed(
+        estimator: &mut Estimator,
+        mode: TimeEstimation,
+        minutes: u64,
+        charge: impl Fn(u64) -> f32,
+        kernel: impl Fn(u64) -> Option<f32>,
+    ) -> Option<f32> {
+        let start = Instant::now();
+        let mut estimation = None;
+        for t in (0..=minutes * 60).step_by(10) {
+            let now = start + Duration::from_secs(t);
+            estimation = estimator.update(mode, now, charge(t), false, kernel(t));
+        }
+        estimation
+    }
+
+    #[test]
+    fn regression_follows_the_charge() {
+        // 1% every 30 seconds, from 60%: 20 minutes to go after the first 10
+        let tte = feed(
+            &mut Estimator::default(),
+            TimeEstimation::Own,
+            10,
+            |t| 60. - (t / 30) as f32,
+            |_| None,
+        )
+        .unwrap();
+        assert!((tte - 20. * 60.).abs() < 60., "{}", tte);
+    }
+
+    #[test]
+    fn regression_sees_less_than_a_percent() {
+        // 5 J every 10 seconds out of 20 kJ: a whole percent only every 7 minutes or so
+        let tte = feed(
+            &mut Estimator::default(),
+            TimeEstimation::Own,
+            3,
+            |t| 20_000. - (t / 2) as f32,
+            |_| None,
+        )
+        .unwrap();
+        assert!((tte - 39_820.).abs() < 60., "{}", tte);
+    }
+
+    #[test]
+    fn regression_needs_some_history() {
+        let mut estimator = Estimator::default();
+        let charge = |t| 60. - (t / 30) as f32;
+        assert_eq!(
+            feed(&mut estimator, TimeEstimation::Auto, 1, charge, |_| None),
+            None
+        );
+        let mut estimator = Estimator::default();
+        assert!(feed(&mut estimator, TimeEstimation::Auto, 3, charge, |_| None).is_some());
+    }
+
+    #[test]
+    fn smooths_kernel_jumps() {
+        // The kernel counts down from an hour, except for one reading of 5 minutes
+        let kernel = |t| Some(if t == 300 { 300. } else { 3600. - t as f32 });
+        let tte = feed(
+            &mut Estimator::default(),
+            TimeEstimation::Auto,
+            5,
+            |_| 50.,
+            kernel,
+        )
+        .unwrap();
+        assert!(tte > 3000., "{}", tte);
+        let tte = feed(
+            &mut Estimator::default(),
+            TimeEstimation::Auto,
+            10,
+            |_| 50.,
+            kernel,
+        )
+        .unwrap();
+        assert!((tte - 3000.).abs() < 60., "{}", tte);
+        let tte = feed(
+            &mut Estimator::default(),
+            TimeEstimation::Kernel,
+            5,
+            |_| 50.,
+            kernel,
+        );
+        assert_eq!(tte, Some(300.));
+    }
+
+    #[test]
+    fn charging_forgets() {
+        let mut estimator = Estimator::default();
+        feed(
+            &mut estimator,
+            TimeEstimation::Own,
+            5,
+            |t| 60. - (t / 30) as f32,
+            |_| None,
+        );
+        let now = Instant::now() + Duration::from_secs(600);
+        assert_eq!(
+            estimator.update(TimeEstimation::Own, now, 50., true, None),
+            None
+        );
+        assert_eq!(
+            estimator.update(TimeEstimation::Own, now, 50., false, None),
+            None
+        );
+    }
+}
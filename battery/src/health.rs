@@ -0,0 +1,418 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Battery wear: how much of their design capacity the batteries still hold, recorded daily under
+//! `$XDG_STATE_HOME/simple-osd/` so that degradation over time can be told
+
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use osd::config::Config;
+use thiserror::Error;
+use xdg::BaseDirectories;
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum HealthError {
+    #[error("Unable to read battery health: {0}")]
+    Battery(#[from] battery::errors::Error),
+    #[error("Failed to set up XDG Base Directories: {0}")]
+    BaseDirectories(#[from] xdg::BaseDirectoriesError),
+    #[error("Failed to access the battery health history {0:?}: {1}")]
+    History(PathBuf, io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    /// Serial number, or vendor and model if there is none; what the history is kept by
+    pub id: String,
+    pub name: String,
+    pub technology: String,
+    /// How much of its design capacity the battery holds, in percent
+    pub health: f32,
+    /// In watt-hours
+    pub energy_full: f32,
+    pub energy_full_design: f32,
+    pub cycle_count: Option<u32>,
+}
+
+impl Health {
+    fn from_battery(battery: &battery::Battery, index: usize) -> Health {
+        let name = [battery.vendor(), battery.model()]
+            .iter()
+            .flatten()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let name = if name.is_empty() {
+            format!("Battery {}", index + 1)
+        } else {
+            name
+        };
+        let id = match battery.serial_number().map(str::trim) {
+            Some(serial) if !serial.is_empty() => format!("{} {}", name, serial),
+            _ => name.clone(),
+        };
+        Health {
+            // Tabs separate the fields of the history
+            id: id.replace('\t', " "),
+            name,
+            technology: battery.technology().to_string(),
+            health: battery.state_of_health().value * 100.,
+            energy_full: battery.energy_full().value / 3600.,
+            energy_full_design: battery.energy_full_design().value / 3600.,
+            cycle_count: battery.cycle_count(),
+        }
+    }
+}
+
+/// Health of every battery in the system
+pub fn read() -> Result<Vec<Health>, HealthError> {
+    let manager = battery::Manager::new()?;
+    let mut healths = Vec::new();
+    for (index, battery) in manager.batteries()?.enumerate() {
+        healths.push(Health::from_battery(&battery?, index));
+    }
+    Ok(healths)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The health of a battery at one point in time, in seconds since the epoch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time: u64,
+    pub id: String,
+    pub health: f32,
+    pub cycle_count: Option<u32>,
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Record> {
+        let mut fields = line.split('\t');
+        let record = Record {
+            time: fields.next()?.parse().ok()?,
+            id: fields.next()?.to_string(),
+            health: fields.next()?.parse().ok()?,
+            cycle_count: match fields.next()? {
+                "-" => None,
+                cycles => Some(cycles.parse().ok()?),
+            },
+        };
+        Some(record).filter(|_| fields.next().is_none())
+    }
+
+    fn line(&self) -> String {
+        let cycles = self
+            .cycle_count
+            .map_or_else(|| String::from("-"), |cycles| cycles.to_string());
+        format!(
+            "{}\t{}\t{:.1}\t{}\n",
+            self.time, self.id, self.health, cycles
+        )
+    }
+}
+
+/// Daily health records of every battery that has been in the system, plus when the user was last
+/// warned about it
+pub struct History {
+    path: PathBuf,
+    records: Vec<Record>,
+}
+
+impl History {
+    /// The history under `$XDG_STATE_HOME/simple-osd/`
+    pub fn open() -> Result<History, HealthError> {
+        let path = BaseDirectories::with_prefix(osd::APPNAME)?
+            .place_state_file("battery-health")
+            .map_err(|err| HealthError::History(PathBuf::from("battery-health"), err))?;
+        History::load(path)
+    }
+
+    pub fn load(path: PathBuf) -> Result<History, HealthError> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(HealthError::History(path, err)),
+        };
+        let records = contents
+            .lines()
+            .filter_map(|line| {
+                let record = Record::parse(line);
+                if record.is_none() {
+                    warn!("Skipping malformed battery health record {:?}", line);
+                }
+                record
+            })
+            .collect();
+        Ok(History { path, records })
+    }
+
+    /// Records of the battery `id`, oldest first
+    pub fn of<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a Record> {
+        self.records.iter().filter(move |record| record.id == id)
+    }
+
+    /// Record the health of `healths` at `time`, unless it has already been recorded that day
+    pub fn record(&mut self, time: u64, healths: &[Health]) -> Result<(), HealthError> {
+        let new: Vec<Record> = healths
+            .iter()
+            .filter(|health| {
+                self.of(&health.id)
+                    .last()
+                    .is_none_or(|last| time >= last.time + DAY)
+            })
+            .map(|health| Record {
+                time,
+                id: health.id.clone(),
+                health: health.health,
+                cycle_count: health.cycle_count,
+            })
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let lines: String = new.iter().map(Record::line).collect();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .map_err(|err| HealthError::History(self.path.clone(), err))?;
+        self.records.extend(new);
+        Ok(())
+    }
+
+    fn warned_path(&self) -> PathBuf {
+        self.path.with_file_name("battery-health-warned")
+    }
+
+    /// When the user was last warned about battery health
+    pub fn warned(&self) -> Option<u64> {
+        fs::read_to_string(self.warned_path())
+            .ok()
+            .and_then(|time| time.trim().parse().ok())
+    }
+
+    pub fn set_warned(&self, time: u64) -> Result<(), HealthError> {
+        let path = self.warned_path();
+        fs::write(&path, format!("{}\n", time)).map_err(|err| HealthError::History(path, err))
+    }
+}
+
+/// A readable report of `healths`, with how they changed since the oldest record in `history`
+pub fn report(healths: &[Health], history: &History, time: u64) -> String {
+    let mut report = String::new();
+    for health in healths {
+        let _ = writeln!(report, "{}", health.name);
+        let _ = writeln!(report, "  Technology: {}", health.technology);
+        let _ = writeln!(
+            report,
+            "  Health:     {:.1}% ({:.1} Wh of {:.1} Wh design)",
+            health.health, health.energy_full, health.energy_full_design
+        );
+        if let Some(cycles) = health.cycle_count {
+            let _ = writeln!(report, "  Cycles:     {}", cycles);
+        }
+        match history.of(&health.id).next() {
+            Some(first) if time >= first.time + DAY => {
+                let _ = writeln!(
+                    report,
+                    "  Change:     {:+.1}% over {} days, from {:.1}%",
+                    health.health - first.health,
+                    (time - first.time) / DAY,
+                    first.health
+                );
+            }
+            _ => {}
+        }
+    }
+    if healths.is_empty() {
+        report.push_str("No batteries detected\n");
+    }
+    report
+}
+
+/// Print the health report and record it
+pub fn print_report() -> Result<(), HealthError> {
+    let healths = read()?;
+    let mut history = History::open()?;
+    let time = now();
+    print!("{}", report(&healths, &history, time));
+    history.record(time, &healths)
+}
+
+/// When to warn that a battery has worn out
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthWarning {
+    /// Warn below this health, in percent; never if `None`
+    pub threshold: Option<f32>,
+    /// How long to wait before warning again
+    pub interval: Duration,
+}
+
+impl HealthWarning {
+    pub fn load(config: &mut Config) -> HealthWarning {
+        let notify = config.get_default("health", "notify", false);
+        let threshold = config.get_default("health", "threshold", String::from("80%"));
+        let threshold = threshold
+            .strip_suffix('%')
+            .and_then(|t| t.parse().ok())
+            .filter(|t| (0. ..=100.).contains(t))
+            .or_else(|| {
+                warn!(
+                    "Health threshold {} is incorrect: must be a percentage; not warning about \
+                     worn out batteries",
+                    threshold
+                );
+                None
+            });
+        let days = config.get_default("health", "interval", 30);
+        HealthWarning {
+            threshold: threshold.filter(|_| notify),
+            interval: Duration::from_secs(DAY * days),
+        }
+    }
+
+    /// The worn out batteries to warn about at `time`, if a warning is due
+    pub fn due<'a>(
+        &self,
+        healths: &'a [Health],
+        warned: Option<u64>,
+        time: u64,
+    ) -> Vec<&'a Health> {
+        let Some(threshold) = self.threshold else {
+            return Vec::new();
+        };
+        if warned.is_some_and(|warned| time < warned + self.interval.as_secs()) {
+            return Vec::new();
+        }
+        healths
+            .iter()
+            .filter(|health| health.health < threshold)
+            .collect()
+    }
+}
+
+/// Record the battery health and tell which batteries to warn about, if a warning is due
+pub fn check(warning: &HealthWarning) -> Result<Vec<Health>, HealthError> {
+    let healths = read()?;
+    let mut history = History::open()?;
+    let time = now();
+    history.record(time, &healths)?;
+
+    let due: Vec<Health> = warning
+        .due(&healths, history.warned(), time)
+        .into_iter()
+        .cloned()
+        .collect();
+    if !due.is_empty() {
+        history.set_warned(time)?;
+    }
+    Ok(due)
+}
+
+#[cfg(test)]
+mod health_tests {
+    use super::*;
+
+    fn health(health: f32) -> Health {
+        Health {
+            id: String::from("ACME Cell 1234"),
+            name: String::from("ACME Cell"),
+            technology: String::from("lithium-ion"),
+            health,
+            energy_full: health / 2.,
+            energy_full_design: 50.,
+            cycle_count: Some(300),
+        }
+    }
+
+    fn history(name: &str) -> History {
+        let path =
+            std::env::temp_dir().join(format!("simple-osd-health-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        History::load(path).unwrap()
+    }
+
+    #[test]
+    fn records_once_a_day() {
+        let mut history = history("daily");
+        history.record(1000, &[health(95.)]).unwrap();
+        history.record(1000 + DAY / 2, &[health(94.)]).unwrap();
+        history.record(1000 + DAY, &[health(93.)]).unwrap();
+
+        let reloaded = History::load(history.path.clone()).unwrap();
+        let recorded: Vec<(u64, f32)> = reloaded
+            .of("ACME Cell 1234")
+            .map(|record| (record.time, record.health))
+            .collect();
+        assert_eq!(recorded, vec![(1000, 95.), (1000 + DAY, 93.)]);
+        let _ = fs::remove_file(&history.path);
+    }
+
+    #[test]
+    fn parses_records() {
+        assert_eq!(
+            Record::parse("86400\tACME Cell\t87.5\t-"),
+            Some(Record {
+                time: 86400,
+                id: String::from("ACME Cell"),
+                health: 87.5,
+                cycle_count: None,
+            })
+        );
+        assert_eq!(Record::parse("86400\tACME Cell\tlots\t-"), None);
+    }
+
+    #[test]
+    fn reports_degradation() {
+        let mut history = history("report");
+        history.record(0, &[health(95.)]).unwrap();
+        let report = report(&[health(80.)], &history, 90 * DAY);
+        assert_eq!(
+            report,
+            "ACME Cell
+  Technology: lithium-ion
+  Health:     80.0% (40.0 Wh of 50.0 Wh design)
+  Cycles:     300
+  Change:     -15.0% over 90 days, from 95.0%
+"
+        );
+        let _ = fs::remove_file(&history.path);
+    }
+
+    #[test]
+    fn warns_monthly_below_threshold() {
+        let warning = HealthWarning {
+            threshold: Some(80.),
+            interval: Duration::from_secs(30 * DAY),
+        };
+        let healths = [health(75.)];
+        assert_eq!(warning.due(&healths, None, 0).len(), 1);
+        assert!(warning.due(&healths, Some(0), 29 * DAY).is_empty());
+        assert_eq!(warning.due(&healths, Some(0), 30 * DAY).len(), 1);
+        assert!(warning.due(&[health(85.)], None, 0).is_empty());
+        assert!(HealthWarning::load(&mut Config::empty())
+            .due(&healths, None, 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn broken_threshold_turns_warnings_off() {
+        let mut config = Config::empty();
+        config.get_default("health", "notify", true);
+        config.get_default("health", "threshold", String::from("0.8"));
+        assert_eq!(HealthWarning::load(&mut config).threshold, None);
+    }
+}
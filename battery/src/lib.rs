@@ -0,0 +1,2371 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+extern crate battery;
+extern crate simple_osd_common as osd;
+extern crate thiserror;
+extern crate xdg;
+extern crate zbus;
+#[macro_use]
+extern crate log;
+
+mod action;
+mod estimate;
+mod health;
+mod limit;
+mod peripheral;
+mod power_profiles;
+mod power_supply;
+mod signals;
+mod upower;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::os::unix::io::AsFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use osd::config::Config;
+use osd::daemon::ready;
+use osd::control::{Command, Control, Handle};
+use osd::event::{EventLoop, EventSender};
+use osd::notify::{OSDContents, OSDProgressText, Style, Urgency, OSD};
+use osd::supervisor::Backoff;
+use osd::uevent::UeventSocket;
+use thiserror::Error;
+
+use action::{Action, CriticalAction, Escalation, Step};
+use estimate::{Estimator, TimeEstimation};
+use health::{HealthError, HealthWarning};
+use limit::ChargeLimit;
+use peripheral::{Kind, Peripheral};
+use power_profiles::{PowerProfiles, Profile};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Threshold {
+    Percentage(i32),
+    Minutes(i32),
+}
+
+impl Threshold {
+    /// Minutes thresholds aren't reached as long as there is no estimation
+    fn reached(&self, soc: i32, tte: Option<i32>) -> bool {
+        match *self {
+            Threshold::Percentage(p) => soc <= p,
+            Threshold::Minutes(m) => tte.is_some_and(|tte| tte <= m),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State {
+    /// Below the threshold of `Settings::levels[i]` (and of every level before it)
+    Level(usize),
+    Charging,
+    /// On AC and charged up to the charge limit
+    Charged,
+    /// On AC and full for longer than `ChargeLimit::full_warning`
+    KeptFull,
+    /// On AC but discharging, with a charger of so many watts
+    Draining(Option<i32>),
+    Normal,
+}
+
+fn threshold_sane(thresh: Threshold) -> Option<Threshold> {
+    match thresh {
+        Threshold::Percentage(p) => {
+            if !(0..=100).contains(&p) {
+                return None;
+            }
+            Some(thresh)
+        }
+        Threshold::Minutes(m) => {
+            if m < 0 {
+                return None;
+            }
+            Some(thresh)
+        }
+    }
+}
+
+fn parse_threshold(thresh: String) -> Option<Threshold> {
+    let mut s = thresh;
+
+    let last = s.pop();
+
+    let parsed = s.parse();
+
+    match last {
+        Some('%') => parsed
+            .map(Threshold::Percentage)
+            .ok()
+            .and_then(threshold_sane),
+        Some('m') => parsed.map(Threshold::Minutes).ok().and_then(threshold_sane),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod parse_threshold_tests {
+    use super::parse_threshold;
+    use super::Threshold;
+    #[test]
+    fn parses_percentage() {
+        assert_eq!(
+            parse_threshold("15%".to_string()),
+            Some(Threshold::Percentage(15))
+        );
+    }
+    #[test]
+    fn parses_minutes() {
+        assert_eq!(
+            parse_threshold("10m".to_string()),
+            Some(Threshold::Minutes(10))
+        );
+    }
+    #[test]
+    fn fails_on_incorrect_percentage() {
+        assert_eq!(parse_threshold("foo%".to_string()), None);
+    }
+    #[test]
+    fn fails_on_incorrect_minutes() {
+        assert_eq!(parse_threshold("foom".to_string()), None);
+    }
+    #[test]
+    fn fails_on_high_percentage() {
+        assert_eq!(parse_threshold("110%".to_string()), None);
+    }
+    #[test]
+    fn fails_on_negative_percentage() {
+        assert_eq!(parse_threshold("-10%".to_string()), None);
+    }
+    #[test]
+    fn fails_on_negative_minutes() {
+        assert_eq!(parse_threshold("-10m".to_string()), None);
+    }
+}
+
+fn format_duration(duration: f32) -> String {
+    let mut d = duration as i32;
+    if d == 0 {
+        return "0s".to_string();
+    }
+    let mut s = String::new();
+    if d < 0 {
+        s.push('-');
+        d = -d;
+    }
+    let hours = d / 3600;
+    let minutes = (d % 3600) / 60;
+    let seconds = d % 60;
+
+    if hours > 0 {
+        s.push_str(&format!("{}h", hours));
+    }
+    if minutes > 0 {
+        if hours > 0 {
+            s.push(' ');
+        }
+        s.push_str(&format!("{}m", minutes));
+    }
+    if seconds > 0 {
+        if hours > 0 || minutes > 0 {
+            s.push(' ');
+        }
+        s.push_str(&format!("{}s", seconds));
+    }
+    s
+}
+
+#[cfg(test)]
+mod format_duration_tests {
+    use super::format_duration;
+    #[test]
+    fn no_time() {
+        assert_eq!(&format_duration(0.), "0s");
+    }
+    #[test]
+    fn seconds() {
+        assert_eq!(&format_duration(12.), "12s");
+    }
+    #[test]
+    fn minutes_seconds() {
+        assert_eq!(&format_duration(123.), "2m 3s");
+    }
+    #[test]
+    fn minutes() {
+        assert_eq!(&format_duration(120.), "2m");
+    }
+    #[test]
+    fn hours_minutes_seconds() {
+        assert_eq!(&format_duration(12345.), "3h 25m 45s");
+    }
+    #[test]
+    fn hours_minutes() {
+        assert_eq!(&format_duration(9000.), "2h 30m")
+    }
+    #[test]
+    fn hours() {
+        assert_eq!(&format_duration(3600.), "1h")
+    }
+    #[test]
+    fn negative() {
+        assert_eq!(&format_duration(-12345.), "-3h 25m 45s");
+    }
+}
+
+/// How often a warning is shown again while the battery stays at its level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    Once,
+    /// Every this many minutes
+    Every(i32),
+    /// Whenever the charge has dropped by this many more percent
+    Drop(i32),
+}
+
+impl FromStr for Repeat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s, parse_threshold(s.to_string())) {
+            ("once", _) => Ok(Repeat::Once),
+            (_, Some(Threshold::Minutes(m))) if m > 0 => Ok(Repeat::Every(m)),
+            (_, Some(Threshold::Percentage(p))) if p > 0 => Ok(Repeat::Drop(p)),
+            _ => Err(format!(
+                "Incorrect repeat {}, must be once, minutes (10m) or a percentage (5%)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Repeat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Repeat::Once => write!(f, "once"),
+            Repeat::Every(m) => write!(f, "{}m", m),
+            Repeat::Drop(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+/// How the warning of a level keeps reminding about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reminders {
+    repeat: Repeat,
+    /// Keep the OSD until it is dismissed
+    persistent: bool,
+    /// How long the snooze button holds off reminders; no button if zero
+    snooze: Duration,
+}
+
+impl Reminders {
+    fn load(config: &mut Config, level: &str, repeat: Repeat, snooze: u64) -> Reminders {
+        Reminders {
+            repeat: config.get_default(level, "repeat", repeat),
+            persistent: config.get_default(level, "persistent", false),
+            snooze: Duration::from_secs(60 * config.get_default(level, "snooze", snooze)),
+        }
+    }
+
+    /// Whether the warning is due again, `elapsed` after it was shown and `dropped` percent lower
+    fn due(&self, elapsed: Duration, dropped: i32) -> bool {
+        match self.repeat {
+            Repeat::Once => false,
+            Repeat::Every(m) => elapsed >= Duration::from_secs(60 * m as u64),
+            Repeat::Drop(p) => dropped >= p,
+        }
+    }
+}
+
+#[cfg(test)]
+mod reminders_tests {
+    use super::*;
+
+    fn reminders(repeat: &str) -> Reminders {
+        Reminders {
+            repeat: repeat.parse().unwrap(),
+            persistent: false,
+            snooze: Duration::from_secs(0),
+        }
+    }
+
+    #[test]
+    fn parses_repeat() {
+        assert_eq!("once".parse(), Ok(Repeat::Once));
+        assert_eq!("10m".parse(), Ok(Repeat::Every(10)));
+        assert_eq!("5%".parse(), Ok(Repeat::Drop(5)));
+        assert!("0%".parse::<Repeat>().is_err());
+        assert!("often".parse::<Repeat>().is_err());
+    }
+
+    #[test]
+    fn once_is_never_due() {
+        assert!(!reminders("once").due(Duration::from_secs(3600), 50));
+    }
+
+    #[test]
+    fn due_after_minutes() {
+        let reminders = reminders("5m");
+        assert!(!reminders.due(Duration::from_secs(299), 10));
+        assert!(reminders.due(Duration::from_secs(300), 0));
+    }
+
+    #[test]
+    fn due_after_drop() {
+        let reminders = reminders("2%");
+        assert!(!reminders.due(Duration::from_secs(3600), 1));
+        assert!(reminders.due(Duration::from_secs(1), 2));
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BatteryError {
+    #[error("Unable to access battery information")]
+    BatteryInformationAccess(#[from] battery::errors::Error),
+    #[error("No batteries detected")]
+    NoBatteriesDetected,
+    #[error("Failed to update a notification: {0}")]
+    OSDUpdate(#[from] osd::notify::UpdateError),
+    #[error("Event loop failed: {0}")]
+    EventLoop(#[from] std::io::Error),
+    #[error("Unable to get battery information from UPower: {0}")]
+    UPower(#[from] zbus::Error),
+    #[error("{0}")]
+    Health(#[from] HealthError),
+    #[error("Unable to read batteries from {0:?}: {1}")]
+    Directory(PathBuf, std::io::Error),
+}
+
+/// Where battery information comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BackendKind {
+    /// UPower if it is running, sysfs otherwise
+    Auto,
+    UPower,
+    Sysfs,
+    /// `directory` rather than `/sys/class/power_supply`, read without the `battery` crate
+    Directory,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BackendKind::Auto),
+            "upower" => Ok(BackendKind::UPower),
+            "sysfs" => Ok(BackendKind::Sysfs),
+            "directory" => Ok(BackendKind::Directory),
+            _ => Err(format!(
+                "Unknown battery backend {}, must be auto, upower, sysfs or directory",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BackendKind::Auto => "auto",
+            BackendKind::UPower => "upower",
+            BackendKind::Sysfs => "sysfs",
+            BackendKind::Directory => "directory",
+        })
+    }
+}
+
+/// A named warning level, such as `low` or `critical`
+struct Level {
+    name: String,
+    threshold: Threshold,
+    urgency: Urgency,
+    icon: String,
+    /// Title of the warning, with `{percentage}` and `{time}` (remaining) filled in
+    template: String,
+    /// Title of the warning while there's no estimation of the time remaining
+    template_without_time: String,
+    reminders: Reminders,
+    /// Performed once the battery gets to this level
+    action: CriticalAction,
+}
+
+impl Level {
+    /// `None` if the level has no valid threshold
+    fn load(config: &mut Config, name: &str) -> Option<Level> {
+        let (threshold, urgency, icon, title, repeat, snooze) = match name {
+            "low" => (
+                "15%",
+                "normal",
+                "battery-low",
+                "Low battery {percentage}%",
+                Repeat::Once,
+                30,
+            ),
+            "critical" => (
+                "5%",
+                "critical",
+                "battery-caution",
+                "Critically low battery {percentage}%",
+                Repeat::Drop(1),
+                10,
+            ),
+            _ => (
+                "",
+                "normal",
+                "battery-low",
+                "Battery {percentage}%",
+                Repeat::Once,
+                30,
+            ),
+        };
+        let template = format!("{}, {{time}} remaining", title);
+
+        let threshold = config.get_default("threshold", name, String::from(threshold));
+        let threshold = match parse_threshold(threshold.clone()) {
+            Some(threshold) => threshold,
+            None if threshold.is_empty() => {
+                warn!(
+                    "Level {} has no threshold in [threshold], ignoring it",
+                    name
+                );
+                return None;
+            }
+            None => {
+                warn!(
+                    "Threshold {} of level {} is incorrect: must be either a percentage or \
+                     minutes; ignoring the level",
+                    threshold, name
+                );
+                return None;
+            }
+        };
+        let urgency = config.get_default(name, "urgency", String::from(urgency));
+
+        Some(Level {
+            name: name.to_string(),
+            threshold,
+            urgency: Urgency::try_from(urgency.as_str()).unwrap_or_else(|_| {
+                warn!(
+                    "Unknown urgency {} of level {}, using normal",
+                    urgency, name
+                );
+                Urgency::Normal
+            }),
+            icon: config.get_default(name, "icon", String::from(icon)),
+            template: config.get_default(name, "template", template),
+            template_without_time: config.get_default(
+                name,
+                "template without time",
+                String::from(title),
+            ),
+            reminders: Reminders::load(config, name, repeat, snooze),
+            action: CriticalAction::load(config, name),
+        })
+    }
+
+    fn title(&self, reading: &Reading) -> String {
+        let template = match reading.time_to_empty {
+            Some(tte) => self.template.replace("{time}", &format_duration(tte)),
+            None => {
+                static WARNED: Once = Once::new();
+                WARNED.call_once(|| warn!("No time-to-empty estimation available"));
+                self.template_without_time.clone()
+            }
+        };
+        template.replace("{percentage}", &reading.soc.to_string())
+    }
+}
+
+/// How a kind of battery warns about itself
+struct Warnings {
+    /// From the least to the most severe
+    levels: Vec<Level>,
+    /// Show the charge whenever the adapter is plugged in or out
+    show_battery_charge: bool,
+    charge_limit: ChargeLimit,
+}
+
+impl Warnings {
+    fn load(config: &mut Config) -> Warnings {
+        let levels = config.get_default("threshold", "levels", String::from("low, critical"));
+
+        Warnings {
+            levels: levels
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| Level::load(config, name))
+                .collect(),
+            show_battery_charge: config.get_default("default", "show battery charge", false),
+            charge_limit: ChargeLimit::load(config),
+        }
+    }
+
+    /// A single `low` level from the `[peripherals]` section
+    fn load_peripheral(config: &mut Config, kind: Kind) -> Warnings {
+        let low = config.get_default("peripherals", "low", String::from("15%"));
+        let threshold = config.get_default("peripherals", &format!("{} low", kind.name()), low);
+
+        Warnings {
+            levels: vec![Level {
+                name: String::from("low"),
+                // Peripherals don't estimate how long they will last
+                threshold: parse_threshold(threshold.clone())
+                    .filter(|threshold| matches!(threshold, Threshold::Percentage(_)))
+                    .unwrap_or_else(|| {
+                        warn!(
+                            "Low threshold {} of {} batteries is incorrect: must be a \
+                             percentage; using 15%",
+                            threshold,
+                            kind.name()
+                        );
+                        Threshold::Percentage(15)
+                    }),
+                urgency: Urgency::Normal,
+                icon: kind.icon().to_string(),
+                template: String::from("Low battery {percentage}%"),
+                template_without_time: String::from("Low battery {percentage}%"),
+                reminders: Reminders::load(config, "peripherals", Repeat::Once, 0),
+                action: CriticalAction::default(),
+            }],
+            show_battery_charge: false,
+            charge_limit: ChargeLimit::default(),
+        }
+    }
+
+    /// The level `state` is at, if it is a warning
+    fn level(&self, state: &State) -> Option<&Level> {
+        match state {
+            State::Level(i) => self.levels.get(*i),
+            State::Charging
+            | State::Charged
+            | State::KeptFull
+            | State::Draining(_)
+            | State::Normal => None,
+        }
+    }
+
+    /// The action of the most severe level up to `levels[i]`, if there is one
+    fn action(&self, i: usize) -> Option<&CriticalAction> {
+        self.levels[..=i]
+            .iter()
+            .rev()
+            .map(|level| &level.action)
+            .find(|action| action.action != Action::Nothing)
+    }
+}
+
+struct Settings {
+    /// Warnings of the system batteries
+    batteries: Warnings,
+    /// Also warn about mice, keyboards, headsets and such
+    watch_peripherals: bool,
+    peripherals: HashMap<Kind, Warnings>,
+    refresh_interval: Duration,
+    /// Treat all batteries as one, rather than alerting about each of them
+    combine_batteries: bool,
+    backend: BackendKind,
+    /// Show when a charger is plugged in or out
+    notify_charger: bool,
+    /// Warn when the battery discharges even though a charger is plugged in
+    weak_charger_warning: bool,
+    /// Show when the power profile changes
+    notify_power_profile: bool,
+    /// Switch to the power-saver profile when the battery gets this low, and back on AC
+    power_saver: Option<Threshold>,
+    /// Read by the `directory` backend
+    directory: PathBuf,
+    time_estimation: TimeEstimation,
+    health: HealthWarning,
+}
+
+impl Settings {
+    fn load(config: &mut Config) -> Settings {
+        Settings {
+            batteries: Warnings::load(config),
+            watch_peripherals: config.get_default("peripherals", "enabled", true),
+            peripherals: Kind::ALL
+                .iter()
+                .map(|&kind| (kind, Warnings::load_peripheral(config, kind)))
+                .collect(),
+            refresh_interval: Duration::from_secs(
+                config.get_default("default", "refresh interval", 1),
+            ),
+            combine_batteries: config.get_default("default", "combine batteries", true),
+            backend: config.get_default("default", "backend", BackendKind::Auto),
+            notify_charger: config.get_default("charger", "notify", true),
+            weak_charger_warning: config.get_default("charger", "weak warning", true),
+            notify_power_profile: config.get_default("power profile", "notify", true),
+            power_saver: {
+                let threshold =
+                    config.get_default("power profile", "power saver", String::from("none"));
+                match threshold.as_str() {
+                    "none" | "" => None,
+                    _ => parse_threshold(threshold.clone()).or_else(|| {
+                        warn!(
+                            "Power saver threshold {} is incorrect: must be either a percentage \
+                             or minutes",
+                            threshold
+                        );
+                        None
+                    }),
+                }
+            },
+            directory: PathBuf::from(config.get_default(
+                "default",
+                "directory",
+                String::from(POWER_SUPPLY),
+            )),
+            time_estimation: config.get_default("default", "time estimation", TimeEstimation::Auto),
+            health: HealthWarning::load(config),
+        }
+    }
+
+    /// Warnings of a system battery, or of a peripheral of `kind`
+    fn warnings(&self, kind: Option<Kind>) -> &Warnings {
+        kind.and_then(|kind| self.peripherals.get(&kind))
+            .unwrap_or(&self.batteries)
+    }
+
+    /// How often to read the batteries; backends that tell when they change don't need to be
+    /// polled, unless something has to be timed while nothing changes
+    fn polling_interval(&self, event_driven: bool) -> Option<Duration> {
+        let timed = self.batteries.charge_limit.full_warning > Duration::from_secs(0);
+        Some(self.refresh_interval).filter(|_| !event_driven || timed)
+    }
+}
+
+/// Raw numbers of one battery, in joules and watts
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    energy: f32,
+    energy_full: f32,
+    energy_rate: f32,
+    state: battery::State,
+}
+
+impl Sample {
+    fn from_battery(battery: &battery::Battery) -> Sample {
+        Sample {
+            energy: battery.energy().value,
+            energy_full: battery.energy_full().value,
+            energy_rate: battery.energy_rate().value,
+            state: battery.state(),
+        }
+    }
+}
+
+/// What the daemon needs to know about a battery (or all of them) at one point in time
+#[derive(Debug, Clone, PartialEq)]
+struct Reading {
+    /// State of charge, in percent
+    soc: i32,
+    /// What is left, in joules where the battery tells, otherwise in percent, for estimating the
+    /// time-to-empty from how it goes down
+    charge: f32,
+    charging: bool,
+    /// In seconds
+    time_to_empty: Option<f32>,
+    /// In seconds
+    time_to_full: Option<f32>,
+}
+
+impl Reading {
+    /// Combine batteries as if they were one: the state of charge is weighted by capacity, and
+    /// the times are those of the total energy at the total rate; an unknown state counts as
+    /// charging `on_ac`, as that is how a battery held at its charge limit looks to the battery
+    /// crate
+    fn from_samples(samples: &[Sample], on_ac: bool) -> Reading {
+        let energy: f32 = samples.iter().map(|s| s.energy).sum();
+        let energy_full: f32 = samples.iter().map(|s| s.energy_full).sum();
+        let rate = |state| -> f32 {
+            samples
+                .iter()
+                .filter(|s| s.state == state)
+                .map(|s| s.energy_rate.abs())
+                .sum()
+        };
+        let discharge_rate = rate(battery::State::Discharging);
+        let charge_rate = rate(battery::State::Charging);
+
+        let charging = discharge_rate == 0.
+            && samples.iter().any(|s| match s.state {
+                battery::State::Charging | battery::State::Full => true,
+                battery::State::Unknown => on_ac,
+                _ => false,
+            });
+
+        Reading {
+            soc: if energy_full > 0. {
+                (energy / energy_full * 100.) as i32
+            } else {
+                0
+            },
+            charge: energy,
+            charging,
+            time_to_empty: Some(energy / discharge_rate).filter(|t| !charging && t.is_finite()),
+            time_to_full: Some((energy_full - energy) / charge_rate)
+                .filter(|t| charging && t.is_finite()),
+        }
+    }
+}
+
+/// How a battery is called in per-battery alerts
+fn battery_name(battery: &battery::Battery, index: usize) -> String {
+    let name = [battery.vendor(), battery.model()]
+        .iter()
+        .flatten()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if name.is_empty() {
+        format!("Battery {}", index + 1)
+    } else {
+        name
+    }
+}
+
+fn classify(reading: &Reading, warnings: &Warnings) -> State {
+    if reading.charging {
+        return State::Charging;
+    }
+
+    let tte = reading.time_to_empty.map(|t| t as i32 / 60);
+    let mut state = State::Normal;
+    for (i, level) in warnings.levels.iter().enumerate() {
+        if level.threshold.reached(reading.soc, tte) {
+            state = State::Level(i);
+        }
+    }
+    state
+}
+
+/// Tell a battery that is done charging from one that is still charging: it is `Charged` from
+/// when it gets within 1% of the charge `limit` until it is unplugged, and `KeptFull` once it has
+/// been full for too long
+fn refine_charging(
+    state: State,
+    last_state: State,
+    soc: i32,
+    limit: Option<i32>,
+    kept_full: bool,
+) -> State {
+    match state {
+        State::Charging if kept_full => State::KeptFull,
+        State::Charging
+            if limit.is_some_and(|limit| soc + 1 >= limit)
+                || matches!(last_state, State::Charged | State::KeptFull) =>
+        {
+            State::Charged
+        }
+        _ => state,
+    }
+}
+
+/// Show whatever `state` calls for, with how many watts the `charger` delivers if it says;
+/// `force` shows an OSD even if nothing has changed
+#[allow(clippy::too_many_arguments)]
+fn show(
+    osd: &mut OSD,
+    config: &mut Config,
+    warnings: &Warnings,
+    reading: &Reading,
+    charger: Option<f32>,
+    state: &State,
+    last_state: &State,
+    force: bool,
+) {
+    let soc = reading.soc;
+
+    let reminders = warnings.level(state).map(|level| &level.reminders);
+    osd.timeout = match reminders {
+        Some(reminders) if reminders.persistent => 0,
+        _ => osd.style.timeout,
+    };
+    osd.actions = match reminders {
+        Some(reminders) if reminders.snooze > Duration::from_secs(0) => vec![(
+            String::from("snooze"),
+            format!(
+                "Snooze for {}",
+                format_duration(reminders.snooze.as_secs_f32())
+            ),
+        )],
+        _ => Vec::new(),
+    };
+
+    if state != last_state || force {
+        match state {
+            State::Charging => {
+                osd.icon = if warnings.show_battery_charge {
+                    let icon_name = format!("battery-{:03}-charging", (soc / 10) * 10);
+                    Some(config.get_override("icons", icon_name.as_str()))
+                } else {
+                    Some(config.get_override("icons", "battery-good-charging"))
+                };
+                osd.urgency = Urgency::Low;
+                let charging = match charger {
+                    Some(watts) => format!("Charging {}% with a {:.0} W charger", soc, watts),
+                    None => format!("Charging {}%", soc),
+                };
+                osd.title = Some(match reading.time_to_full {
+                    Some(ttf) => format!("{}, {} until full", charging, format_duration(ttf)),
+                    None => {
+                        debug!("No time-to-full estimation available");
+                        charging
+                    }
+                });
+                osd.update_();
+            }
+            State::Charged => {
+                osd.icon = Some(config.get_override("icons", "battery-full-charged"));
+                osd.urgency = Urgency::Low;
+                osd.title = Some(format!("Charged to {}%, you can unplug", soc));
+                osd.update_();
+            }
+            State::KeptFull => {
+                osd.icon = Some(config.get_override("icons", "battery-full-charged"));
+                osd.urgency = Urgency::Normal;
+                osd.title = Some(format!(
+                    "Battery has been full for {}, unplug to preserve it",
+                    format_duration(warnings.charge_limit.full_warning.as_secs_f32())
+                ));
+                osd.update_();
+            }
+            State::Draining(watts) => {
+                osd.icon = Some(config.get_override("icons", "battery-caution"));
+                osd.urgency = Urgency::Normal;
+                let charger = match watts {
+                    Some(watts) => format!("The {} W charger", watts),
+                    None => String::from("The charger"),
+                };
+                osd.title = Some(format!(
+                    "{} can't keep up, battery discharging at {}%",
+                    charger, soc
+                ));
+                osd.update_();
+            }
+            State::Level(_) => {
+                let level = warnings
+                    .level(state)
+                    .expect("States are classified by level");
+                osd.icon = Some(config.get_override("icons", level.icon.as_str()));
+                osd.urgency = level.urgency;
+                osd.title = Some(level.title(reading));
+                osd.update_();
+            }
+            State::Normal if warnings.show_battery_charge || force => {
+                let icon_name = format!("battery-{:03}", (soc / 10) * 10);
+                osd.icon = Some(config.get_override("icons", icon_name.as_str()));
+                osd.urgency = Urgency::Normal;
+                let prefix = if state != last_state {
+                    "Adapter disconnected, charge"
+                } else {
+                    "Battery charge"
+                };
+                osd.title = Some(match reading.time_to_empty {
+                    Some(tte) => format!(
+                        "{} {}%, {} remaining",
+                        prefix,
+                        soc,
+                        format_duration(tte)
+                    ),
+                    None => {
+                        debug!("No time-to-empty estimation available");
+                        format!("{} {}%", prefix, soc)
+                    }
+                });
+                osd.update_();
+            }
+            State::Normal => {}
+        }
+    }
+}
+
+static POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+/// A source of battery readings
+enum Backend {
+    /// Polls `/sys/class/power_supply` through the `battery` crate
+    Sysfs {
+        manager: battery::Manager,
+        battery_count: usize,
+    },
+    /// Asks UPower, which sends a `Refresh` whenever something changes
+    UPower(upower::UPower),
+    /// Polls a directory laid out like `/sys/class/power_supply`
+    Directory(PathBuf),
+}
+
+impl Backend {
+    fn open(settings: &Settings, sender: &EventSender<Event>) -> Result<Backend, BatteryError> {
+        let upower = match settings.backend {
+            BackendKind::Sysfs => return Backend::sysfs(),
+            BackendKind::Directory => return Ok(Backend::Directory(settings.directory.clone())),
+            BackendKind::UPower => upower::UPower::new()?,
+            BackendKind::Auto => match upower::UPower::new() {
+                Ok(upower) => upower,
+                Err(err) => {
+                    info!("UPower is not available ({}), reading sysfs instead", err);
+                    return Backend::sysfs();
+                }
+            },
+        };
+        Backend::watch(upower, sender)
+    }
+
+    fn sysfs() -> Result<Backend, BatteryError> {
+        Ok(Backend::Sysfs {
+            manager: battery::Manager::new()?,
+            battery_count: 0,
+        })
+    }
+
+    fn watch(upower: upower::UPower, sender: &EventSender<Event>) -> Result<Backend, BatteryError> {
+        let sender = sender.clone();
+        upower.watch(move || sender.send(Event::Refresh))?;
+        Ok(Backend::UPower(upower))
+    }
+
+    /// Whether readings only need to be taken on `Refresh` events, rather than periodically
+    fn event_driven(&self) -> bool {
+        matches!(self, Backend::UPower(_))
+    }
+
+    /// Named readings of each battery, or a single unnamed one when `combine` is set
+    fn readings(&mut self, combine: bool) -> Result<Vec<(String, Reading)>, BatteryError> {
+        match self {
+            Backend::Sysfs {
+                manager,
+                battery_count,
+            } => {
+                // Listing the batteries anew every time picks up hot-plugged ones
+                // Leaving out those that go away while they are read
+                let batteries: Vec<_> = manager
+                    .batteries()?
+                    .filter_map(|battery| {
+                        battery
+                            .map_err(|err| debug!("Skipping a battery: {}", err))
+                            .ok()
+                    })
+                    .collect();
+                if batteries.len() != *battery_count {
+                    info!("Found {} batteries", batteries.len());
+                    *battery_count = batteries.len();
+                }
+
+                let samples = batteries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, battery)| (battery_name(battery, i), Sample::from_battery(battery)))
+                    .collect();
+                Ok(Backend::combine(samples, combine, self.on_ac()))
+            }
+            Backend::Directory(root) => {
+                let samples = power_supply::read_batteries(root)
+                    .map_err(|err| BatteryError::Directory(root.clone(), err))?;
+                Ok(Backend::combine(samples, combine, self.on_ac()))
+            }
+            Backend::UPower(upower) => Ok(upower.readings(combine)?),
+        }
+    }
+
+    fn combine(
+        samples: Vec<(String, Sample)>,
+        combine: bool,
+        on_ac: bool,
+    ) -> Vec<(String, Reading)> {
+        if samples.is_empty() {
+            Vec::new()
+        } else if combine {
+            let samples: Vec<Sample> = samples.into_iter().map(|(_, sample)| sample).collect();
+            vec![(String::new(), Reading::from_samples(&samples, on_ac))]
+        } else {
+            samples
+                .into_iter()
+                .map(|(name, sample)| (name, Reading::from_samples(&[sample], on_ac)))
+                .collect()
+        }
+    }
+
+    fn peripherals(&mut self) -> Result<Vec<Peripheral>, BatteryError> {
+        match self {
+            Backend::Sysfs { .. } | Backend::Directory(_) => {
+                peripheral::read_sysfs(self.power_supply()).or_else(|err| {
+                    warn!("Unable to read peripheral batteries: {}", err);
+                    Ok(Vec::new())
+                })
+            }
+            Backend::UPower(upower) => Ok(upower.peripherals()?),
+        }
+    }
+
+    /// Whether a charger is plugged in
+    fn on_ac(&self) -> bool {
+        power_supply::read_adapters(self.power_supply())
+            .unwrap_or_default()
+            .iter()
+            .any(|adapter| adapter.online)
+    }
+
+    /// Where charge limits and such are read from
+    fn power_supply(&self) -> &Path {
+        match self {
+            Backend::Directory(root) => root,
+            Backend::Sysfs { .. } | Backend::UPower(_) => Path::new(POWER_SUPPLY),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Event {
+    Refresh,
+    /// A second of the critical action countdown has passed
+    Countdown,
+    /// The snooze button of a battery has been clicked
+    Snooze(String),
+    /// Time to record the battery health again
+    Health,
+    /// The kernel has sent uevents
+    Uevent,
+    /// Something about power-profiles-daemon has changed
+    PowerProfile,
+    /// Time to try opening the backend again after it has failed
+    Reopen,
+    Control(Command),
+}
+
+/// Show how long is left before the critical action, or that it has been cancelled
+fn show_countdown(osd: &mut OSD, config: &mut Config, settings: &CriticalAction, step: &Step) {
+    match step {
+        Step::Countdown(remaining) => {
+            osd.icon = Some(config.get_override("icons", "battery-caution"));
+            osd.urgency = Urgency::Critical;
+            osd.title = Some(format!(
+                "{} in {}, plug in the charger to cancel",
+                settings.action.describe(),
+                format_duration(remaining.as_secs_f32().ceil())
+            ));
+            osd.contents = OSDContents::Progress(
+                remaining.as_secs_f32() / settings.grace_period.as_secs_f32().max(1.),
+                OSDProgressText::Text(None),
+            );
+        }
+        Step::Cancel => {
+            osd.icon = Some(config.get_override("icons", "battery-good-charging"));
+            osd.urgency = Urgency::Normal;
+            osd.title = Some(format!("Cancelled: {}", settings.action.describe()));
+            osd.contents = OSDContents::Simple(None);
+        }
+        Step::Idle | Step::Perform => return,
+    }
+    osd.update_();
+}
+
+/// A battery (or all of them, when combined) or a peripheral, and its OSD
+struct Tracked {
+    osd: OSD,
+    /// `None` for system batteries
+    kind: Option<Kind>,
+    last_state: State,
+    /// When the OSD was last shown, and at what charge
+    shown_at: Instant,
+    shown_soc: i32,
+    snoozed_until: Option<Instant>,
+    /// Since when it has been full on AC
+    full_since: Option<Instant>,
+    estimator: Estimator,
+}
+
+/// Record the battery health, and warn about the most worn out battery once in a while
+fn check_health(osd: &mut OSD, config: &mut Config, warning: &HealthWarning) {
+    let due = match health::check(warning) {
+        Ok(due) => due,
+        Err(err) => return warn!("{}", err),
+    };
+    if let Some(worst) = due.iter().min_by(|a, b| a.health.total_cmp(&b.health)) {
+        info!("{} is worn out, {:.1}% health", worst.name, worst.health);
+        osd.icon = Some(config.get_override("icons", "battery-caution"));
+        osd.urgency = Urgency::Normal;
+        osd.title = Some(format!(
+            "{} holds {:.0}% of its design capacity, consider replacing it",
+            worst.name, worst.health
+        ));
+        osd.update_();
+    }
+}
+
+/// Print the health of every battery, for `--report`
+pub fn battery_report() -> Result<(), BatteryError> {
+    Ok(health::print_report()?)
+}
+
+/// Tell that a peripheral has been connected
+fn show_connected(osd: &mut OSD, config: &mut Config, kind: Kind, reading: &Reading) {
+    osd.icon = Some(config.get_override("icons", kind.icon()));
+    osd.urgency = Urgency::Low;
+    let name = kind.name();
+    osd.title = Some(format!(
+        "{}{} connected, {}%",
+        name[..1].to_uppercase(),
+        &name[1..],
+        reading.soc
+    ));
+    osd.update_();
+}
+
+/// What the daemon keeps track of between readings
+struct Monitor {
+    config: Config,
+    settings: Settings,
+    /// Of every OSD the monitor shows
+    style: Style,
+    /// Where the snooze buttons send their clicks
+    sender: EventSender<Event>,
+    control: Option<Handle>,
+    tracked: HashMap<String, Tracked>,
+    /// Show the current state on the next update, for `ShowCurrent`
+    show_current: bool,
+    first_reading: bool,
+    escalation: Escalation,
+    last_action: Option<CriticalAction>,
+    /// Not tied to the control object, so that suppressing OSDs doesn't hide the countdown
+    countdown_osd: OSD,
+    /// Whether each adapter is online, as last seen
+    adapters: HashMap<String, bool>,
+    charger_osd: OSD,
+    power_profiles: Option<PowerProfiles>,
+    /// The active power profile, as last seen
+    profile: Option<Profile>,
+    /// The profile to go back to on AC, after switching to power-saver
+    saved_profile: Option<Profile>,
+    profile_osd: OSD,
+}
+
+impl Monitor {
+    fn new(
+        mut config: Config,
+        style: Style,
+        sender: EventSender<Event>,
+        control: Option<Handle>,
+    ) -> Monitor {
+        let mut charger_osd = OSD::builder().style(style.clone()).build();
+        charger_osd.control = control.clone();
+        let mut profile_osd = OSD::builder().style(style.clone()).build();
+        profile_osd.control = control.clone();
+        Monitor {
+            settings: Settings::load(&mut config),
+            config,
+            countdown_osd: OSD::builder().style(style.clone()).build(),
+            charger_osd,
+            style,
+            sender,
+            control,
+            tracked: HashMap::new(),
+            show_current: false,
+            first_reading: true,
+            escalation: Escalation::Idle,
+            last_action: None,
+            adapters: HashMap::new(),
+            power_profiles: None,
+            profile: None,
+            saved_profile: None,
+            profile_osd,
+        }
+    }
+
+    /// Show changes of the power profile, and switch it when the battery runs low
+    fn use_power_profiles(&mut self, power_profiles: PowerProfiles) {
+        self.profile = power_profiles.active().ok();
+        self.power_profiles = Some(power_profiles);
+    }
+
+    /// Take readings from `backend` at `now` and show whatever they call for; the step of the
+    /// critical action tells whether the countdown has to go on
+    fn update(&mut self, backend: &mut Backend, now: Instant) -> Result<Step, BatteryError> {
+        let settings = &self.settings;
+        let config = &mut self.config;
+        let (style, sender, control) = (&self.style, &self.sender, &self.control);
+
+        let mut readings: Vec<(String, Option<Kind>, Reading)> = backend
+            .readings(settings.combine_batteries)?
+            .into_iter()
+            .map(|(name, reading)| (name, None, reading))
+            .collect();
+        if settings.watch_peripherals {
+            readings.extend(
+                backend
+                    .peripherals()?
+                    .into_iter()
+                    .map(|p| (p.name, Some(p.kind), p.reading)),
+            );
+        }
+        self.tracked
+            .retain(|name, _| readings.iter().any(|(n, _, _)| n == name));
+
+        let adapters = power_supply::read_adapters(backend.power_supply()).unwrap_or_default();
+        for adapter in adapters.iter() {
+            // The rest is up to `power_supply_changed`
+            self.adapters
+                .entry(adapter.name.clone())
+                .or_insert(adapter.online);
+        }
+        let charger = adapters.iter().find(|adapter| adapter.online);
+        let mut most_severe = None;
+        // Of the system batteries, for the power-saver profile
+        let (mut low, mut on_ac) = (false, false);
+
+        for (name, kind, reading) in readings.iter() {
+            let warnings = settings.warnings(*kind);
+            let connected = !self.tracked.contains_key(name);
+            let battery = self.tracked.entry(name.clone()).or_insert_with(|| {
+                let mut osd = OSD::builder().style(style.clone()).build();
+                osd.icon = Some(String::from("battery"));
+                osd.control = control.clone();
+                if !name.is_empty() {
+                    osd.contents = OSDContents::Simple(Some(name.clone()));
+                }
+                let sender = sender.clone();
+                let name = name.clone();
+                osd.on_action(Box::new(move |action| {
+                    if action == "snooze" {
+                        sender.send(Event::Snooze(name.clone()));
+                    }
+                }));
+                Tracked {
+                    osd,
+                    kind: *kind,
+                    last_state: State::Normal,
+                    shown_at: now,
+                    shown_soc: reading.soc,
+                    snoozed_until: None,
+                    full_since: None,
+                    estimator: Estimator::default(),
+                }
+            });
+
+            let discharging = reading.time_to_empty.is_some();
+            let reading = &Reading {
+                time_to_empty: battery.estimator.update(
+                    settings.time_estimation,
+                    now,
+                    reading.charge,
+                    reading.charging,
+                    reading.time_to_empty,
+                ),
+                ..reading.clone()
+            };
+            let state = classify(reading, warnings);
+            debug!(device = name.as_str(); "State: {:?}, {:?}", state, reading);
+
+            battery.full_since = if reading.charging && reading.soc >= 100 {
+                battery.full_since.or(Some(now))
+            } else {
+                None
+            };
+            let full_warning = warnings.charge_limit.full_warning;
+            let kept_full = full_warning > Duration::from_secs(0)
+                && battery
+                    .full_since
+                    .is_some_and(|since| now - since >= full_warning);
+            let limit = if reading.charging {
+                warnings.charge_limit.percentage(backend.power_supply())
+            } else {
+                None
+            };
+            let state = refine_charging(state, battery.last_state, reading.soc, limit, kept_full);
+            let state = match (state, charger, kind) {
+                (State::Normal, Some(charger), None)
+                    if discharging && settings.weak_charger_warning =>
+                {
+                    State::Draining(charger.watts.map(|watts| watts.round() as i32))
+                }
+                _ => state,
+            };
+
+            if let Some(kind) = kind {
+                if connected && !self.first_reading {
+                    info!(device = name.as_str(); "Connected, {}%", reading.soc);
+                    show_connected(&mut battery.osd, config, *kind, reading);
+                }
+            }
+
+            let changed = state != battery.last_state;
+            if changed {
+                battery.snoozed_until = None;
+                if let Some(level) = warnings.level(&state) {
+                    info!(device = name.as_str(); "Battery is at the {} level", level.name);
+                }
+            }
+            let snoozed = battery.snoozed_until.is_some_and(|until| now < until);
+            let remind = !changed
+                && !snoozed
+                && warnings.level(&state).is_some_and(|level| {
+                    level
+                        .reminders
+                        .due(now - battery.shown_at, battery.shown_soc - reading.soc)
+                });
+            if remind {
+                debug!(device = name.as_str(); "Reminding about {:?}", state);
+            }
+            if changed || remind || self.show_current {
+                battery.shown_at = now;
+                battery.shown_soc = reading.soc;
+            }
+
+            show(
+                &mut battery.osd,
+                config,
+                warnings,
+                reading,
+                charger
+                    .filter(|_| kind.is_none())
+                    .and_then(|charger| charger.watts),
+                &state,
+                &battery.last_state,
+                self.show_current || remind,
+            );
+            if let (State::Level(i), None) = (state, kind) {
+                most_severe = most_severe.max(Some(i));
+            }
+            if kind.is_none() {
+                match state {
+                    State::Charging | State::Charged | State::KeptFull => on_ac = true,
+                    _ => {
+                        let tte = reading.time_to_empty.map(|t| t as i32 / 60);
+                        low |= settings
+                            .power_saver
+                            .is_some_and(|threshold| threshold.reached(reading.soc, tte));
+                    }
+                }
+            }
+            battery.last_state = state;
+        }
+        self.first_reading = false;
+        self.show_current = false;
+
+        let action = most_severe.and_then(|i| settings.batteries.action(i));
+        let step = self.escalation.step(action, now);
+        if let Some(action) = action {
+            self.last_action = Some(action.clone());
+        }
+        match step {
+            Step::Cancel => info!("Battery has recovered, cancelling the action"),
+            Step::Perform => {
+                if let Some(action) = &self.last_action {
+                    warn!("Battery is running out, performing {}", action.action);
+                    action.perform().unwrap_or_else(|err| error!("{}", err));
+                }
+            }
+            Step::Countdown(_) | Step::Idle => {}
+        }
+        if let Some(action) = &self.last_action {
+            show_countdown(&mut self.countdown_osd, config, action, &step);
+        }
+        if let Some(soc) = readings
+            .iter()
+            .filter(|(_, kind, _)| kind.is_none())
+            .map(|(_, _, reading)| reading.soc)
+            .min()
+        {
+            self.switch_power_profile(low && !on_ac, on_ac, soc);
+        }
+        Ok(step)
+    }
+
+    /// Tell that a charger has been unplugged, if the device `name` is one
+    fn power_supply_changed(&mut self, backend: &Backend, name: &str) {
+        let Some(adapter) = power_supply::read_adapter(&backend.power_supply().join(name)) else {
+            return;
+        };
+        if self.adapters.insert(adapter.name.clone(), adapter.online) == Some(adapter.online) {
+            return;
+        }
+        info!(device = name; "Charger {}", if adapter.online { "connected" } else { "disconnected" });
+        // Plugging in is told by the charging OSD, along with the watts
+        if adapter.online || !self.settings.notify_charger {
+            return;
+        }
+
+        let osd = &mut self.charger_osd;
+        osd.icon = Some(self.config.get_override("icons", "ac-adapter"));
+        osd.urgency = Urgency::Low;
+        osd.title = Some(String::from("Charger disconnected"));
+        osd.update_();
+    }
+
+    /// Switch to power-saver when the battery gets `low`, and back to the profile it replaced once
+    /// `on_ac`, unless the profile has been changed in the meantime
+    fn switch_power_profile(&mut self, low: bool, on_ac: bool, soc: i32) {
+        let Some(power_profiles) = &self.power_profiles else {
+            return;
+        };
+        let (profile, title) = if low && self.saved_profile.is_none() {
+            let active = match power_profiles.active() {
+                Ok(active) => active,
+                Err(err) => return warn!("Unable to get the power profile: {}", err),
+            };
+            self.saved_profile = Some(active);
+            if active == Profile::PowerSaver {
+                return;
+            }
+            (
+                Profile::PowerSaver,
+                format!("Battery at {}%, switched to power saver", soc),
+            )
+        } else if on_ac {
+            let Some(saved) = self.saved_profile.take() else {
+                return;
+            };
+            if saved == Profile::PowerSaver || self.profile != Some(Profile::PowerSaver) {
+                return;
+            }
+            (
+                saved,
+                format!(
+                    "Charging, switched back to {}",
+                    saved.describe().to_lowercase()
+                ),
+            )
+        } else {
+            return;
+        };
+
+        info!("Switching to the {} power profile", profile);
+        if let Err(err) = power_profiles.set_active(profile) {
+            return warn!("Unable to switch to the {} power profile: {}", profile, err);
+        }
+        self.profile = Some(profile);
+        if self.settings.notify_power_profile {
+            let osd = &mut self.profile_osd;
+            osd.icon = Some(self.config.get_override("icons", profile.icon()));
+            osd.urgency = Urgency::Normal;
+            osd.title = Some(title);
+            osd.update_();
+        }
+    }
+
+    /// Tell that the power profile has been changed, unless it is by the daemon itself
+    fn power_profile_changed(&mut self) {
+        let Some(power_profiles) = &self.power_profiles else {
+            return;
+        };
+        let profile = match power_profiles.active() {
+            Ok(profile) => profile,
+            Err(err) => return warn!("Unable to get the power profile: {}", err),
+        };
+        if self.profile.replace(profile) == Some(profile) {
+            return;
+        }
+        info!("Power profile changed to {}", profile);
+        if !self.settings.notify_power_profile {
+            return;
+        }
+
+        let osd = &mut self.profile_osd;
+        osd.icon = Some(self.config.get_override("icons", profile.icon()));
+        osd.urgency = Urgency::Low;
+        osd.title = Some(format!("{} power profile", profile.describe()));
+        osd.update_();
+    }
+
+    fn snooze(&mut self, name: &str, now: Instant) {
+        if let Some(battery) = self.tracked.get_mut(name) {
+            let warnings = self.settings.warnings(battery.kind);
+            if let Some(Level { reminders, .. }) = warnings.level(&battery.last_state) {
+                info!(
+                    device = name;
+                    "Snoozed for {}",
+                    format_duration(reminders.snooze.as_secs_f32())
+                );
+                battery.snoozed_until = Some(now + reminders.snooze);
+            }
+        }
+    }
+}
+
+pub fn battery_daemon() -> Result<(), BatteryError> {
+    let uevents = UeventSocket::open()
+        .map_err(|err| warn!("Unable to listen to uevents: {}", err))
+        .ok();
+
+    let mut events = EventLoop::new()?;
+    if let Some(uevents) = &uevents {
+        events.add_fd(uevents.as_fd(), Event::Uevent)?;
+    }
+    let sender = events.sender();
+    let control = Control::new_or_warn("battery", move |command| {
+        sender.send(Event::Control(command));
+    });
+    let handle = control.handle();
+
+    let mut monitor = Monitor::new(
+        Config::new("battery"),
+        Style::load(&mut Config::new("common")),
+        events.sender(),
+        Some(handle.clone()),
+    );
+    match PowerProfiles::new() {
+        Ok(power_profiles) => {
+            let sender = events.sender();
+            power_profiles
+                .watch(move || sender.send(Event::PowerProfile))
+                .unwrap_or_else(|err| warn!("Unable to watch the power profile: {}", err));
+            monitor.use_power_profiles(power_profiles);
+        }
+        Err(err) => info!("power-profiles-daemon is not available: {}", err),
+    }
+    let refresh = events.add_timer(monitor.settings.refresh_interval, Event::Refresh);
+    let countdown = events.add_timer(Duration::from_secs(1), Event::Countdown);
+    events.set_timer(countdown, None);
+    let reopen = events.add_oneshot(Duration::from_secs(1), Event::Reopen);
+    events.set_timer(reopen, None);
+    let mut backoff = Backoff::default();
+
+    let mut backend = Backend::open(&monitor.settings, &events.sender())?;
+    events.set_timer(
+        refresh,
+        monitor.settings.polling_interval(backend.event_driven()),
+    );
+    if backend
+        .readings(monitor.settings.combine_batteries)?
+        .is_empty()
+    {
+        return Err(BatteryError::NoBatteriesDetected);
+    }
+
+    ready();
+
+    let mut health_osd = OSD::new();
+    health_osd.control = Some(handle);
+    check_health(
+        &mut health_osd,
+        &mut monitor.config,
+        &monitor.settings.health,
+    );
+    events.add_timer(Duration::from_secs(24 * 60 * 60), Event::Health);
+
+    // The backend is `None` while it waits to be reopened
+    let mut backend = Some(backend);
+    loop {
+        let step = match &mut backend {
+            Some(current) => monitor.update(current, Instant::now()),
+            None => Ok(Step::Idle),
+        };
+        match step {
+            Ok(step) => {
+                backoff.reset();
+                match step {
+                    Step::Countdown(_) => events.set_timer(countdown, Some(Duration::from_secs(1))),
+                    Step::Cancel => events.set_timer(countdown, None),
+                    // Look again once the system may have to be put to sleep again
+                    Step::Perform => events.set_timer(
+                        countdown,
+                        monitor
+                            .last_action
+                            .as_ref()
+                            .filter(|action| action.action.sleeps())
+                            .map(|action| action.grace_period.max(Duration::from_secs(1))),
+                    ),
+                    Step::Idle if monitor.escalation == Escalation::Idle => {
+                        events.set_timer(countdown, None)
+                    }
+                    Step::Idle => {}
+                }
+            }
+            // UPower restarting or a battery going away mid-read, most likely
+            Err(err) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Unable to read the batteries: {}; reopening in {:?}",
+                    err, delay
+                );
+                backend = None;
+                events.set_timer(reopen, Some(delay));
+            }
+        }
+
+        match events.wait()? {
+            Event::Refresh | Event::Countdown => {}
+            Event::Reopen => match Backend::open(&monitor.settings, &events.sender()) {
+                Ok(reopened) => {
+                    events.set_timer(
+                        refresh,
+                        monitor.settings.polling_interval(reopened.event_driven()),
+                    );
+                    backend = Some(reopened);
+                }
+                Err(err) => {
+                    let delay = backoff.next_delay();
+                    warn!(
+                        "Unable to reopen the batteries: {}; retrying in {:?}",
+                        err, delay
+                    );
+                    events.set_timer(reopen, Some(delay));
+                }
+            },
+            Event::Health => check_health(
+                &mut health_osd,
+                &mut monitor.config,
+                &monitor.settings.health,
+            ),
+            Event::Uevent => {
+                let received = match &uevents {
+                    Some(uevents) => uevents.receive().unwrap_or_else(|err| {
+                        warn!("Failed to receive uevents: {}", err);
+                        Vec::new()
+                    }),
+                    None => Vec::new(),
+                };
+                for uevent in received {
+                    if let (Some(name), Some(backend)) = (uevent.power_supply(), &backend) {
+                        monitor.power_supply_changed(backend, name);
+                    }
+                }
+            }
+            Event::PowerProfile => monitor.power_profile_changed(),
+            Event::Snooze(name) => monitor.snooze(&name, Instant::now()),
+            Event::Control(Command::ShowCurrent) => monitor.show_current = true,
+            Event::Control(Command::Reload) => {
+                info!("Reloading configuration");
+                let (kind, directory) =
+                    (monitor.settings.backend, monitor.settings.directory.clone());
+                monitor.config = Config::new("battery");
+                monitor.settings = Settings::load(&mut monitor.config);
+                if monitor.settings.backend != kind || monitor.settings.directory != directory {
+                    backend = None;
+                    events.set_timer(reopen, Some(Duration::from_millis(1)));
+                } else if let Some(backend) = &backend {
+                    events.set_timer(
+                        refresh,
+                        monitor.settings.polling_interval(backend.event_driven()),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod osd_tests {
+    use super::*;
+    use simple_osd_test_support::NotificationServer;
+
+    fn warnings() -> Warnings {
+        Settings::load(&mut Config::empty()).batteries
+    }
+
+    fn discharging(soc: i32, minutes: f32) -> Reading {
+        Reading {
+            soc,
+            charge: soc as f32,
+            charging: false,
+            time_to_empty: Some(minutes * 60.),
+            time_to_full: None,
+        }
+    }
+
+    fn sample(energy: f32, energy_full: f32, energy_rate: f32, state: battery::State) -> Sample {
+        Sample {
+            energy,
+            energy_full,
+            energy_rate,
+            state,
+        }
+    }
+
+    #[test]
+    fn combines_batteries_by_energy() {
+        // A nearly empty big internal pack and a full small external one, both discharging
+        let reading = Reading::from_samples(
+            &[
+                sample(10., 80., 5., battery::State::Discharging),
+                sample(20., 20., 5., battery::State::Discharging),
+            ],
+            true,
+        );
+        assert_eq!(reading.soc, 30);
+        assert!(!reading.charging);
+        assert_eq!(reading.time_to_empty, Some(3.));
+        assert_eq!(reading.time_to_full, None);
+    }
+
+    #[test]
+    fn one_discharging_battery_means_discharging() {
+        let reading = Reading::from_samples(
+            &[
+                sample(50., 50., 0., battery::State::Full),
+                sample(10., 50., 10., battery::State::Discharging),
+            ],
+            true,
+        );
+        assert_eq!(reading.soc, 60);
+        assert!(!reading.charging);
+        assert_eq!(reading.time_to_empty, Some(6.));
+    }
+
+    #[test]
+    fn charging_batteries() {
+        let reading = Reading::from_samples(
+            &[
+                sample(50., 50., 0., battery::State::Full),
+                sample(10., 50., 20., battery::State::Charging),
+            ],
+            true,
+        );
+        assert!(reading.charging);
+        assert_eq!(reading.time_to_empty, None);
+        assert_eq!(reading.time_to_full, Some(2.));
+    }
+
+    #[test]
+    fn unknown_state_is_charging_on_ac() {
+        let held = [sample(40., 50., 0., battery::State::Unknown)];
+        assert!(Reading::from_samples(&held, true).charging);
+        assert!(!Reading::from_samples(&held, false).charging);
+    }
+
+    #[test]
+    fn classifies_by_thresholds() {
+        let warnings = warnings();
+        assert_eq!(classify(&discharging(50, 120.), &warnings), State::Normal);
+        assert_eq!(classify(&discharging(15, 30.), &warnings), State::Level(0));
+        assert_eq!(classify(&discharging(5, 10.), &warnings), State::Level(1));
+    }
+
+    #[test]
+    fn critical_battery_is_urgent() {
+        let server = NotificationServer::start();
+        let warnings = warnings();
+        let mut osd = OSD::builder().style(Style::default()).build();
+        let reading = discharging(4, 10.);
+        let state = classify(&reading, &warnings);
+        show(
+            &mut osd,
+            &mut Config::empty(),
+            &warnings,
+            &reading,
+            None,
+            &state,
+            &State::Level(0),
+            false,
+        );
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].urgency, Some(2));
+        assert_eq!(notifications[0].icon, "battery-caution");
+        assert_eq!(
+            notifications[0].summary,
+            "Critically low battery 4%, 10m remaining"
+        );
+    }
+
+    #[test]
+    fn plugging_in_shows_charging() {
+        let server = NotificationServer::start();
+        let warnings = warnings();
+        let mut osd = OSD::builder().style(Style::default()).build();
+        let reading = Reading {
+            soc: 40,
+            charge: 40.,
+            charging: true,
+            time_to_empty: None,
+            time_to_full: Some(3600.),
+        };
+        show(
+            &mut osd,
+            &mut Config::empty(),
+            &warnings,
+            &reading,
+            None,
+            &State::Charging,
+            &State::Normal,
+            false,
+        );
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].urgency, Some(0));
+        assert_eq!(notifications[0].summary, "Charging 40%, 1h until full");
+    }
+
+    #[test]
+    fn charged_up_to_limit() {
+        let charging = State::Charging;
+        assert_eq!(
+            refine_charging(charging, State::Normal, 70, Some(80), false),
+            State::Charging
+        );
+        assert_eq!(
+            refine_charging(charging, State::Charging, 79, Some(80), false),
+            State::Charged
+        );
+        // The charge may sag a bit below the limit while on AC
+        assert_eq!(
+            refine_charging(charging, State::Charged, 76, Some(80), false),
+            State::Charged
+        );
+        assert_eq!(
+            refine_charging(charging, State::Charging, 99, None, false),
+            State::Charging
+        );
+        assert_eq!(
+            refine_charging(charging, State::Charged, 100, None, true),
+            State::KeptFull
+        );
+        assert_eq!(
+            refine_charging(State::Normal, State::Charged, 80, Some(80), false),
+            State::Normal
+        );
+    }
+
+    #[test]
+    fn charged_says_unplug() {
+        let server = NotificationServer::start();
+        let mut warnings = warnings();
+        warnings.charge_limit.full_warning = Duration::from_secs(3 * 60 * 60);
+        let mut osd = OSD::builder().style(Style::default()).build();
+        let reading = Reading {
+            soc: 80,
+            charge: 80.,
+            charging: true,
+            time_to_empty: None,
+            time_to_full: None,
+        };
+        let mut config = Config::empty();
+        show(
+            &mut osd,
+            &mut config,
+            &warnings,
+            &reading,
+            None,
+            &State::Charged,
+            &State::Charging,
+            false,
+        );
+        show(
+            &mut osd,
+            &mut config,
+            &warnings,
+            &reading,
+            None,
+            &State::KeptFull,
+            &State::Charged,
+            false,
+        );
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].summary, "Charged to 80%, you can unplug");
+        assert_eq!(notifications[0].icon, "battery-full-charged");
+        assert_eq!(
+            notifications[1].summary,
+            "Battery has been full for 3h, unplug to preserve it"
+        );
+        assert_eq!(notifications[1].urgency, Some(1));
+    }
+
+    #[test]
+    fn critical_action_countdown() {
+        let server = NotificationServer::start();
+        let action = CriticalAction {
+            action: action::Action::Suspend,
+            command: String::new(),
+            grace_period: Duration::from_secs(60),
+        };
+        let mut osd = OSD::builder().style(Style::default()).build();
+        show_countdown(
+            &mut osd,
+            &mut Config::empty(),
+            &action,
+            &Step::Countdown(Duration::from_secs(45)),
+        );
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].urgency, Some(2));
+        assert_eq!(
+            notifications[0].summary,
+            "Suspending in 45s, plug in the charger to cancel"
+        );
+        assert_eq!(notifications[0].body.trim_end(), "███████████████░░░░░");
+    }
+
+    #[test]
+    fn persistent_critical_warning_can_be_snoozed() {
+        let server = NotificationServer::start();
+        let mut warnings = warnings();
+        warnings.levels[1].reminders.persistent = true;
+        let mut osd = OSD::builder().style(Style::default()).build();
+        show(
+            &mut osd,
+            &mut Config::empty(),
+            &warnings,
+            &discharging(4, 10.),
+            None,
+            &State::Level(1),
+            &State::Level(1),
+            true,
+        );
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].expire_timeout, 0);
+        assert_eq!(
+            notifications[0].actions,
+            vec![(String::from("snooze"), String::from("Snooze for 10m"))]
+        );
+    }
+
+    #[test]
+    fn unchanged_critical_state_is_not_shown_again() {
+        let server = NotificationServer::start();
+        let warnings = warnings();
+        let mut osd = OSD::builder().style(Style::default()).build();
+        show(
+            &mut osd,
+            &mut Config::empty(),
+            &warnings,
+            &discharging(4, 10.),
+            None,
+            &State::Level(1),
+            &State::Level(1),
+            false,
+        );
+        assert!(server.notifications().is_empty());
+    }
+
+    #[test]
+    fn custom_levels() {
+        let mut config = Config::empty();
+        let settings = Settings::load(&mut config);
+        assert_eq!(
+            settings.batteries.levels.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(),
+            vec!["low", "critical"]
+        );
+
+        let warning = Level {
+            name: String::from("warning"),
+            threshold: Threshold::Percentage(25),
+            template: String::from("Battery {percentage}%, {time} remaining"),
+            ..Level::load(&mut config, "low").unwrap()
+        };
+        let shutdown = Level {
+            name: String::from("shutdown"),
+            threshold: Threshold::Minutes(2),
+            ..Level::load(&mut config, "critical").unwrap()
+        };
+        let warnings = Warnings {
+            levels: vec![warning, shutdown],
+            show_battery_charge: false,
+            charge_limit: ChargeLimit::default(),
+        };
+        assert_eq!(classify(&discharging(20, 60.), &warnings), State::Level(0));
+        assert_eq!(classify(&discharging(20, 1.), &warnings), State::Level(1));
+        // Without an estimation, the minutes threshold just isn't reached
+        let unknown = Reading {
+            time_to_empty: None,
+            ..discharging(20, 1.)
+        };
+        assert_eq!(classify(&unknown, &warnings), State::Level(0));
+        assert_eq!(
+            warnings.levels[0].title(&discharging(20, 90.)),
+            "Battery 20%, 1h 30m remaining"
+        );
+        assert_eq!(warnings.levels[1].title(&unknown), "Critically low battery 20%");
+    }
+
+    #[test]
+    fn broken_levels_are_ignored() {
+        let mut config = Config::empty();
+        // Nothing can set values directly, but missing ones are filled in with the default
+        let levels = String::from("low, warning, critical");
+        config.get_default("threshold", "levels", levels);
+        config.get_default("threshold", "critical", String::from("almost empty"));
+        config.get_default("peripherals", "mouse low", String::from("10m"));
+        let settings = Settings::load(&mut config);
+        let levels = &settings.batteries.levels;
+        assert_eq!(levels.len(), 1);
+        assert_eq!(levels[0].name, "low");
+        assert_eq!(
+            settings.warnings(Some(Kind::Mouse)).levels[0].threshold,
+            Threshold::Percentage(15)
+        );
+    }
+
+    #[test]
+    fn most_severe_action_applies() {
+        let mut config = Config::empty();
+        let mut settings = Settings::load(&mut config);
+        let warnings = &mut settings.batteries;
+        warnings.levels[0].action.action = action::Action::Command;
+        assert_eq!(warnings.action(1).unwrap().action, action::Action::Command);
+        warnings.levels[1].action.action = action::Action::Suspend;
+        assert_eq!(warnings.action(1).unwrap().action, action::Action::Suspend);
+        assert_eq!(warnings.action(0).unwrap().action, action::Action::Command);
+    }
+
+    #[test]
+    fn peripheral_connected_and_low() {
+        let server = NotificationServer::start();
+        let settings = Settings::load(&mut Config::empty());
+        let mut osd = OSD::builder()
+            .style(Style::default())
+            .contents(OSDContents::Simple(Some(String::from("MX Master 3"))))
+            .build();
+        show_connected(&mut osd, &mut Config::empty(), Kind::Mouse, &discharging(40, 0.));
+
+        let warnings = settings.warnings(Some(Kind::Mouse));
+        let reading = Reading {
+            soc: 12,
+            charge: 12.,
+            charging: false,
+            time_to_empty: None,
+            time_to_full: None,
+        };
+        let state = classify(&reading, warnings);
+        assert_eq!(state, State::Level(0));
+        show(
+            &mut osd,
+            &mut Config::empty(),
+            warnings,
+            &reading,
+            None,
+            &state,
+            &State::Normal,
+            false,
+        );
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].summary, "Mouse connected, 40%");
+        assert_eq!(notifications[0].icon, "input-mouse");
+        assert_eq!(notifications[0].body, "MX Master 3");
+        assert_eq!(notifications[1].summary, "Low battery 12%");
+        assert_eq!(notifications[1].icon, "input-mouse");
+    }
+}
+
+#[cfg(test)]
+mod monitor_tests {
+    use super::*;
+    use simple_osd_test_support::NotificationServer;
+    use std::fs;
+
+    /// A directory laid out like `/sys/class/power_supply`
+    struct Fixture {
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let root = std::env::temp_dir().join(format!(
+                "simple-osd-monitor-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Fixture { root }
+        }
+
+        fn write(&self, device: &str, attributes: &[(&str, &str)]) {
+            let device = self.root.join(device);
+            fs::create_dir_all(&device).unwrap();
+            for (attribute, value) in attributes {
+                fs::write(device.join(attribute), format!("{}\n", value)).unwrap();
+            }
+        }
+
+        /// A 50 Wh battery at `soc`, drawing or taking 10 W
+        fn battery(&self, device: &str, soc: i32, status: &str) {
+            self.write(
+                device,
+                &[
+                    ("type", "Battery"),
+                    ("status", status),
+                    ("energy_full", "50000000"),
+                    ("energy_now", &(soc * 500_000).to_string()),
+                    ("power_now", "10000000"),
+                ],
+            );
+        }
+
+        fn backend(&self) -> Backend {
+            Backend::Directory(self.root.clone())
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn monitor(events: &EventLoop<'static, Event>) -> Monitor {
+        let mut monitor = Monitor::new(Config::empty(), Style::default(), events.sender(), None);
+        monitor.settings.time_estimation = TimeEstimation::Kernel;
+        monitor.settings.watch_peripherals = false;
+        monitor
+    }
+
+    fn summaries(server: &NotificationServer) -> Vec<String> {
+        server
+            .notifications()
+            .into_iter()
+            .map(|notification| notification.summary)
+            .collect()
+    }
+
+    #[test]
+    fn discharging_through_the_levels() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("levels");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        let start = Instant::now();
+        let mut update = |minutes: u64| {
+            let now = start + Duration::from_secs(60 * minutes);
+            monitor.update(&mut backend, now).unwrap()
+        };
+
+        fixture.battery("BAT0", 50, "Discharging");
+        update(0);
+        assert!(summaries(&server).is_empty());
+
+        fixture.battery("BAT0", 15, "Discharging");
+        update(1);
+        fixture.battery("BAT0", 5, "Discharging");
+        update(2);
+        fixture.battery("BAT0", 5, "Charging");
+        update(3);
+        fixture.battery("BAT0", 5, "Discharging");
+        update(4);
+        assert_eq!(
+            summaries(&server),
+            vec![
+                "Low battery 15%, 45m remaining",
+                "Critically low battery 5%, 15m remaining",
+                "Charging 5%, 4h 45m until full",
+                "Critically low battery 5%, 15m remaining",
+            ]
+        );
+        let notifications = server.notifications();
+        assert_eq!(notifications[1].urgency, Some(2));
+        assert_eq!(notifications[2].icon, "battery-good-charging");
+    }
+
+    #[test]
+    fn unplugging_is_quiet_by_default() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("unplug");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        let now = Instant::now();
+
+        fixture.battery("BAT0", 60, "Charging");
+        monitor.update(&mut backend, now).unwrap();
+        fixture.battery("BAT0", 60, "Discharging");
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(summaries(&server), vec!["Charging 60%, 2h until full"]);
+
+        monitor.show_current = true;
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(summaries(&server)[1], "Battery charge 60%, 3h remaining");
+    }
+
+    #[test]
+    fn batteries_alert_on_their_own() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("separate");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let now = Instant::now();
+
+        fixture.battery("BAT0", 50, "Discharging");
+        fixture.battery("BAT1", 10, "Discharging");
+        fixture.write("BAT1", &[("manufacturer", "ACME"), ("model_name", "Slice")]);
+        let mut combined = monitor(&events);
+        combined.update(&mut backend, now).unwrap();
+        assert!(summaries(&server).is_empty());
+
+        let mut separate = monitor(&events);
+        separate.settings.combine_batteries = false;
+        separate.update(&mut backend, now).unwrap();
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].summary, "Low battery 10%, 30m remaining");
+        assert_eq!(notifications[0].body, "ACME Slice");
+    }
+
+    #[test]
+    fn charge_limit_from_the_directory() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("limit");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        let now = Instant::now();
+
+        fixture.battery("BAT0", 79, "Charging");
+        fixture.write("BAT0", &[("charge_control_end_threshold", "80")]);
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(summaries(&server), vec!["Charged to 79%, you can unplug"]);
+    }
+
+    #[test]
+    fn held_at_the_charge_limit() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("held");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        monitor.settings.batteries.show_battery_charge = true;
+        let now = Instant::now();
+
+        usb_charger(&fixture, "1");
+        fixture.battery("BAT0", 79, "Charging");
+        fixture.write("BAT0", &[("charge_control_end_threshold", "80")]);
+        monitor.update(&mut backend, now).unwrap();
+        fixture.battery("BAT0", 80, "Not charging");
+        monitor.update(&mut backend, now).unwrap();
+        // What the battery crate makes of "Not charging"
+        fixture.battery("BAT0", 80, "Unknown");
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(summaries(&server), vec!["Charged to 79%, you can unplug"]);
+    }
+
+    fn usb_charger(fixture: &Fixture, online: &str) {
+        fixture.write(
+            "ucsi-source-psy-USBC000:001",
+            &[
+                ("type", "USB"),
+                ("online", online),
+                ("voltage_max", "20000000"),
+                ("current_max", "3250000"),
+            ],
+        );
+    }
+
+    #[test]
+    fn charger_plugged_in() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("charger");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        let now = Instant::now();
+
+        usb_charger(&fixture, "0");
+        fixture.battery("BAT0", 60, "Discharging");
+        monitor.update(&mut backend, now).unwrap();
+
+        usb_charger(&fixture, "1");
+        fixture.battery("BAT0", 60, "Charging");
+        monitor.power_supply_changed(&backend, "ucsi-source-psy-USBC000:001");
+        monitor.power_supply_changed(&backend, "ucsi-source-psy-USBC000:001");
+        monitor.power_supply_changed(&backend, "BAT0");
+        monitor.update(&mut backend, now).unwrap();
+
+        usb_charger(&fixture, "0");
+        monitor.power_supply_changed(&backend, "ucsi-source-psy-USBC000:001");
+        assert_eq!(
+            summaries(&server),
+            vec![
+                "Charging 60% with a 65 W charger, 2h until full",
+                "Charger disconnected",
+            ]
+        );
+        assert_eq!(server.notifications()[1].icon, "ac-adapter");
+    }
+
+    #[test]
+    fn weak_charger() {
+        let server = NotificationServer::start();
+        let fixture = Fixture::new("weak-charger");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+
+        usb_charger(&fixture, "1");
+        fixture.battery("BAT0", 60, "Discharging");
+        monitor.update(&mut backend, Instant::now()).unwrap();
+        assert_eq!(
+            summaries(&server),
+            vec!["The 65 W charger can't keep up, battery discharging at 60%"]
+        );
+    }
+
+    #[test]
+    fn power_saver_when_low() {
+        let server = NotificationServer::start();
+        let _service = power_profiles::power_profiles_tests::mock_power_profiles(server.bus());
+        let fixture = Fixture::new("power-saver");
+        let mut backend = fixture.backend();
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        monitor.settings.power_saver = Some(Threshold::Percentage(20));
+        monitor.use_power_profiles(PowerProfiles::with_connection(server.bus().connect()).unwrap());
+        let active = || {
+            PowerProfiles::with_connection(server.bus().connect())
+                .unwrap()
+                .active()
+                .unwrap()
+        };
+        let now = Instant::now();
+
+        fixture.battery("BAT0", 50, "Discharging");
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(active(), Profile::Balanced);
+
+        fixture.battery("BAT0", 20, "Discharging");
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(active(), Profile::PowerSaver);
+        // The change the daemon has made itself isn't shown twice
+        monitor.power_profile_changed();
+
+        fixture.battery("BAT0", 20, "Charging");
+        monitor.update(&mut backend, now).unwrap();
+        assert_eq!(active(), Profile::Balanced);
+        monitor.power_profile_changed();
+        assert_eq!(
+            summaries(&server),
+            vec![
+                "Battery at 20%, switched to power saver",
+                "Charging 20%, 4h until full",
+                "Charging, switched back to balanced",
+            ]
+        );
+        assert_eq!(
+            server.notifications()[0].icon,
+            "power-profile-power-saver-symbolic"
+        );
+    }
+
+    #[test]
+    fn power_profile_changed_elsewhere() {
+        let server = NotificationServer::start();
+        let _service = power_profiles::power_profiles_tests::mock_power_profiles(server.bus());
+        let events = EventLoop::new().unwrap();
+        let mut monitor = monitor(&events);
+        monitor.use_power_profiles(PowerProfiles::with_connection(server.bus().connect()).unwrap());
+
+        monitor.power_profile_changed();
+        assert!(summaries(&server).is_empty());
+        PowerProfiles::with_connection(server.bus().connect())
+            .unwrap()
+            .set_active(Profile::Performance)
+            .unwrap();
+        monitor.power_profile_changed();
+        assert_eq!(summaries(&server), vec!["Performance power profile"]);
+    }
+}
@@ -0,0 +1,122 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Charge limits, which keep batteries of laptops that live on AC from wearing out
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use osd::config::Config;
+
+/// Where the charge limit comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// `charge_control_end_threshold` of the batteries, if they have one
+    Auto,
+    Percentage(i32),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%').map(str::parse) {
+            _ if s == "auto" => Ok(Target::Auto),
+            Some(Ok(p)) if (1..=100).contains(&p) => Ok(Target::Percentage(p)),
+            _ => Err(format!(
+                "Incorrect charge limit {}, must be auto or a percentage",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Auto => write!(f, "auto"),
+            Target::Percentage(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ChargeLimit {
+    /// Tell when the battery has charged up to the limit; `None` to never tell
+    pub target: Option<Target>,
+    /// Warn when the battery stays full on AC for this long; never if zero
+    pub full_warning: Duration,
+}
+
+impl ChargeLimit {
+    pub fn load(config: &mut Config) -> ChargeLimit {
+        let notify = config.get_default("charge limit", "notify", true);
+        let target = config.get_default("charge limit", "target", Target::Auto);
+        let hours = config.get_default("charge limit", "full warning", 0);
+        ChargeLimit {
+            target: Some(target).filter(|_| notify),
+            full_warning: Duration::from_secs(60 * 60 * hours),
+        }
+    }
+
+    /// The charge limit in percent, with `root` laid out like `/sys/class/power_supply`
+    pub fn percentage(&self, root: &Path) -> Option<i32> {
+        match self.target? {
+            Target::Auto => end_threshold(root),
+            Target::Percentage(p) => Some(p),
+        }
+    }
+}
+
+/// The lowest `charge_control_end_threshold` of the system batteries under `root`
+pub fn end_threshold(root: &Path) -> Option<i32> {
+    fs::read_dir(root)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|device| {
+            fs::read_to_string(device.join("scope")).map_or(true, |scope| scope.trim() != "Device")
+        })
+        .filter_map(|device| fs::read_to_string(device.join("charge_control_end_threshold")).ok())
+        .filter_map(|threshold| threshold.trim().parse().ok())
+        .filter(|&threshold| threshold < 100)
+        .min()
+}
+
+#[cfg(test)]
+mod limit_tests {
+    use super::*;
+
+    #[test]
+    fn parses_target() {
+        assert_eq!("auto".parse(), Ok(Target::Auto));
+        assert_eq!("80%".parse(), Ok(Target::Percentage(80)));
+        assert!("0%".parse::<Target>().is_err());
+        assert!("80".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn reads_end_threshold() {
+        let root = std::env::temp_dir().join(format!("simple-osd-limit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (device, scope, threshold) in [
+            ("BAT0", "System", "80"),
+            ("BAT1", "System", "100"),
+            ("hid-mouse", "Device", "60"),
+        ] {
+            fs::create_dir_all(root.join(device)).unwrap();
+            fs::write(root.join(device).join("scope"), scope).unwrap();
+            fs::write(
+                root.join(device).join("charge_control_end_threshold"),
+                format!("{}\n", threshold),
+            )
+            .unwrap();
+        }
+        assert_eq!(end_threshold(&root), Some(80));
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(end_threshold(&root), None);
+    }
+}
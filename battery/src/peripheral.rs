@@ -0,0 +1,228 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Batteries of wireless mice, keyboards, headsets and the like, which `/sys/class/power_supply`
+//! marks with `scope=Device`
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Reading;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Mouse,
+    Keyboard,
+    Headset,
+    Other,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 4] = [Kind::Mouse, Kind::Keyboard, Kind::Headset, Kind::Other];
+
+    /// As used in the configuration and in OSDs
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Mouse => "mouse",
+            Kind::Keyboard => "keyboard",
+            Kind::Headset => "headset",
+            Kind::Other => "device",
+        }
+    }
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            Kind::Mouse => "input-mouse",
+            Kind::Keyboard => "input-keyboard",
+            Kind::Headset => "audio-headset",
+            Kind::Other => "battery",
+        }
+    }
+
+    /// From the `Type` property of a UPower device
+    pub fn from_upower(type_: u32) -> Kind {
+        match type_ {
+            5 => Kind::Mouse,
+            6 => Kind::Keyboard,
+            17 | 19 => Kind::Headset,
+            _ => Kind::Other,
+        }
+    }
+
+    /// sysfs doesn't tell what a device is, but its name usually does
+    fn guess(name: &str) -> Kind {
+        let name = name.to_lowercase();
+        if name.contains("mouse") {
+            Kind::Mouse
+        } else if name.contains("keyboard") || name.contains("kbd") {
+            Kind::Keyboard
+        } else if name.contains("headset") || name.contains("headphone") {
+            Kind::Headset
+        } else {
+            Kind::Other
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peripheral {
+    pub name: String,
+    pub kind: Kind,
+    pub reading: Reading,
+}
+
+pub fn attribute(device: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(device.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn read_device(device: &Path) -> Option<Peripheral> {
+    if attribute(device, "scope")? != "Device" || attribute(device, "type")? != "Battery" {
+        return None;
+    }
+
+    let soc = match attribute(device, "capacity") {
+        Some(capacity) => capacity.parse().ok()?,
+        // Some devices only report a rough level
+        None => match attribute(device, "capacity_level")?.as_str() {
+            "Full" => 100,
+            "High" => 80,
+            "Normal" => 50,
+            "Low" => 10,
+            "Critical" => 2,
+            _ => return None,
+        },
+    };
+    let charging = matches!(
+        attribute(device, "status").as_deref(),
+        Some("Charging") | Some("Full")
+    );
+
+    let file_name = device.file_name()?.to_string_lossy().into_owned();
+    let name = [attribute(device, "manufacturer"), attribute(device, "model_name")]
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let name = if name.is_empty() { file_name } else { name };
+
+    Some(Peripheral {
+        kind: Kind::guess(&name),
+        name,
+        reading: Reading {
+            soc,
+            charge: soc as f32,
+            charging,
+            time_to_empty: None,
+            time_to_full: None,
+        },
+    })
+}
+
+/// Peripheral batteries under `root`, which is laid out like `/sys/class/power_supply`
+pub fn read_sysfs(root: &Path) -> io::Result<Vec<Peripheral>> {
+    let mut peripherals = Vec::new();
+    for entry in fs::read_dir(root)? {
+        if let Some(peripheral) = read_device(&entry?.path()) {
+            peripherals.push(peripheral);
+        }
+    }
+    peripherals.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peripherals)
+}
+
+#[cfg(test)]
+mod peripheral_tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str, devices: &[(&str, &[(&str, &str)])]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "simple-osd-peripherals-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        for (device, attributes) in devices {
+            let device = root.join(device);
+            fs::create_dir_all(&device).unwrap();
+            for (attribute, value) in attributes.iter() {
+                fs::write(device.join(attribute), format!("{}\n", value)).unwrap();
+            }
+        }
+        root
+    }
+
+    #[test]
+    fn reads_device_batteries_only() {
+        let root = fixture(
+            "scope",
+            &[
+                (
+                    "BAT0",
+                    &[("type", "Battery"), ("scope", "System"), ("capacity", "50")],
+                ),
+                (
+                    "hidpp_battery_0",
+                    &[
+                        ("type", "Battery"),
+                        ("scope", "Device"),
+                        ("capacity", "40"),
+                        ("status", "Discharging"),
+                        ("manufacturer", "Logitech"),
+                        ("model_name", "Wireless Mouse MX Master 3"),
+                    ],
+                ),
+                (
+                    "ps-controller-battery",
+                    &[
+                        ("type", "Battery"),
+                        ("scope", "Device"),
+                        ("capacity_level", "Low"),
+                        ("status", "Charging"),
+                    ],
+                ),
+            ],
+        );
+
+        let peripherals = read_sysfs(&root).unwrap();
+        assert_eq!(
+            peripherals,
+            vec![
+                Peripheral {
+                    name: String::from("Logitech Wireless Mouse MX Master 3"),
+                    kind: Kind::Mouse,
+                    reading: Reading {
+                        soc: 40,
+                        charge: 40.,
+                        charging: false,
+                        time_to_empty: None,
+                        time_to_full: None,
+                    },
+                },
+                Peripheral {
+                    name: String::from("ps-controller-battery"),
+                    kind: Kind::Other,
+                    reading: Reading {
+                        soc: 10,
+                        charge: 10.,
+                        charging: true,
+                        time_to_empty: None,
+                        time_to_full: None,
+                    },
+                },
+            ]
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn guesses_kind() {
+        assert_eq!(Kind::guess("Apple Magic Keyboard"), Kind::Keyboard);
+        assert_eq!(Kind::guess("WH-1000XM4 Headphones"), Kind::Headset);
+    }
+}
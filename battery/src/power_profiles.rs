@@ -0,0 +1,234 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! The active profile of power-profiles-daemon, which the daemon shows when it changes and can
+//! switch to power-saver when the battery runs low
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use zbus::blocking::Connection;
+use zbus::message::Type as MessageType;
+use zbus::proxy::CacheProperties;
+use zbus::{proxy, MatchRule};
+
+use crate::signals;
+
+static SERVICE: &str = "net.hadess.PowerProfiles";
+static PATH: &str = "/net/hadess/PowerProfiles";
+
+#[proxy(
+    interface = "net.hadess.PowerProfiles",
+    default_service = "net.hadess.PowerProfiles",
+    default_path = "/net/hadess/PowerProfiles",
+    gen_async = false,
+    blocking_name = "PowerProfilesProxy"
+)]
+trait PowerProfiles {
+    #[zbus(property)]
+    fn active_profile(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn set_active_profile(&self, profile: &str) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    PowerSaver,
+    Balanced,
+    Performance,
+}
+
+impl Profile {
+    /// How the profile is called in OSDs
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Profile::PowerSaver => "Power saver",
+            Profile::Balanced => "Balanced",
+            Profile::Performance => "Performance",
+        }
+    }
+
+    /// Named as in GNOME's quick settings
+    pub fn icon(&self) -> &'static str {
+        match self {
+            Profile::PowerSaver => "power-profile-power-saver-symbolic",
+            Profile::Balanced => "power-profile-balanced-symbolic",
+            Profile::Performance => "power-profile-performance-symbolic",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "power-saver" => Ok(Profile::PowerSaver),
+            "balanced" => Ok(Profile::Balanced),
+            "performance" => Ok(Profile::Performance),
+            _ => Err(format!(
+                "Unknown power profile {}, must be power-saver, balanced or performance",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Profile::PowerSaver => "power-saver",
+            Profile::Balanced => "balanced",
+            Profile::Performance => "performance",
+        })
+    }
+}
+
+pub struct PowerProfiles {
+    connection: Connection,
+    /// Tells the signal watching thread to stop
+    stopped: Arc<AtomicBool>,
+}
+
+impl PowerProfiles {
+    /// Connect to power-profiles-daemon on the system bus
+    pub fn new() -> zbus::Result<PowerProfiles> {
+        PowerProfiles::with_connection(Connection::system()?)
+    }
+
+    pub fn with_connection(connection: Connection) -> zbus::Result<PowerProfiles> {
+        let profiles = PowerProfiles {
+            connection,
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        // Fail early if power-profiles-daemon isn't there
+        profiles.active()?;
+        Ok(profiles)
+    }
+
+    fn proxy(&self) -> zbus::Result<PowerProfilesProxy<'static>> {
+        PowerProfilesProxy::builder(&self.connection)
+            .cache_properties(CacheProperties::No)
+            .build()
+    }
+
+    pub fn active(&self) -> zbus::Result<Profile> {
+        self.proxy()?
+            .active_profile()?
+            .parse()
+            .map_err(zbus::Error::Failure)
+    }
+
+    /// Needs the permission of polkit, which active sessions usually have
+    pub fn set_active(&self, profile: Profile) -> zbus::Result<()> {
+        self.proxy()?.set_active_profile(&profile.to_string())
+    }
+
+    /// Call `on_change` from another thread whenever the properties of power-profiles-daemon
+    /// change, until it returns false or this is dropped
+    pub fn watch<F>(&self, on_change: F) -> zbus::Result<()>
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(SERVICE)?
+            .path(PATH)?
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .build();
+        signals::watch(
+            &self.connection,
+            rule,
+            "power-profiles-daemon",
+            self.stopped.clone(),
+            on_change,
+        )
+    }
+}
+
+impl Drop for PowerProfiles {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod power_profiles_tests {
+    use super::*;
+    use simple_osd_test_support::Bus;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use zbus::interface;
+
+    pub(crate) struct MockPowerProfiles {
+        active_profile: String,
+    }
+
+    #[interface(name = "net.hadess.PowerProfiles")]
+    impl MockPowerProfiles {
+        #[zbus(property)]
+        fn active_profile(&self) -> String {
+            self.active_profile.clone()
+        }
+
+        #[zbus(property)]
+        fn set_active_profile(&mut self, profile: String) {
+            self.active_profile = profile;
+        }
+    }
+
+    /// power-profiles-daemon in the balanced profile, for as long as the connection lives
+    pub(crate) fn mock_power_profiles(bus: &Bus) -> Connection {
+        bus.serve(
+            SERVICE,
+            PATH,
+            MockPowerProfiles {
+                active_profile: String::from("balanced"),
+            },
+        )
+    }
+
+    #[test]
+    fn switches_profiles() {
+        let bus = Bus::start();
+        let service = mock_power_profiles(&bus);
+        let profiles = PowerProfiles::with_connection(bus.connect()).unwrap();
+        assert_eq!(profiles.active().unwrap(), Profile::Balanced);
+
+        profiles.set_active(Profile::PowerSaver).unwrap();
+        let mock = service
+            .object_server()
+            .interface::<_, MockPowerProfiles>(PATH)
+            .unwrap();
+        assert_eq!(mock.get().active_profile, "power-saver");
+        assert_eq!(profiles.active().unwrap(), Profile::PowerSaver);
+    }
+
+    #[test]
+    fn fails_without_power_profiles_daemon() {
+        let bus = Bus::start();
+        assert!(PowerProfiles::with_connection(bus.connect()).is_err());
+    }
+
+    #[test]
+    fn profile_changes_are_watched() {
+        let bus = Bus::start();
+        let _service = mock_power_profiles(&bus);
+        let profiles = PowerProfiles::with_connection(bus.connect()).unwrap();
+        let (sender, receiver) = channel();
+        profiles.watch(move || sender.send(()).is_ok()).unwrap();
+
+        // As if changed from the quick settings of the desktop
+        let other = PowerProfiles::with_connection(bus.connect()).unwrap();
+        other.set_active(Profile::Performance).unwrap();
+
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No change reported");
+        assert_eq!(profiles.active().unwrap(), Profile::Performance);
+    }
+}
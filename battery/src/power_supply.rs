@@ -0,0 +1,211 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! System batteries read straight from a directory laid out like `/sys/class/power_supply`, which
+//! may as well be a fixture of a test
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::peripheral::attribute;
+use crate::Sample;
+
+/// An attribute in micro-units (µWh, µAh, µW, µA or µV), in units
+fn micro(device: &Path, name: &str) -> Option<f32> {
+    attribute(device, name)?
+        .parse::<f32>()
+        .ok()
+        .map(|value| value / 1_000_000.)
+}
+
+fn read_battery(device: &Path) -> Option<Sample> {
+    if attribute(device, "type")? != "Battery"
+        || attribute(device, "scope").as_deref() == Some("Device")
+        || attribute(device, "present").as_deref() == Some("0")
+    {
+        return None;
+    }
+
+    // Some batteries report energy, others charge, which the voltage turns into energy
+    let voltage = micro(device, "voltage_min_design").or_else(|| micro(device, "voltage_now"));
+    let watt_hours =
+        |energy, charge| micro(device, energy).or_else(|| Some(micro(device, charge)? * voltage?));
+    let energy = watt_hours("energy_now", "charge_now")?;
+    let energy_full = watt_hours("energy_full", "charge_full")?;
+    let rate = micro(device, "power_now")
+        .or_else(|| Some(micro(device, "current_now")? * micro(device, "voltage_now")?))
+        .unwrap_or(0.);
+
+    Some(Sample {
+        energy: energy * 3600.,
+        energy_full: energy_full * 3600.,
+        energy_rate: rate.abs(),
+        state: match attribute(device, "status").as_deref() {
+            // On AC but held at the charge limit, which the battery crate doesn't know about
+            Some("Not charging") => battery::State::Full,
+            status => status
+                .and_then(|status| status.parse().ok())
+                .unwrap_or(battery::State::Unknown),
+        },
+    })
+}
+
+/// Named samples of the system batteries under `root`
+pub fn read_batteries(root: &Path) -> io::Result<Vec<(String, Sample)>> {
+    let mut devices = fs::read_dir(root)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    devices.sort();
+
+    let mut batteries = Vec::new();
+    for device in devices {
+        if let Some(sample) = read_battery(&device) {
+            let name = [
+                attribute(&device, "manufacturer"),
+                attribute(&device, "model_name"),
+            ]
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+            let name = if name.is_empty() {
+                format!("Battery {}", batteries.len() + 1)
+            } else {
+                name
+            };
+            batteries.push((name, sample));
+        }
+    }
+    Ok(batteries)
+}
+
+/// An external power source: an AC adapter or a USB charger
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adapter {
+    pub name: String,
+    pub online: bool,
+    /// What it can deliver, e.g. as negotiated over USB-PD
+    pub watts: Option<f32>,
+}
+
+pub fn read_adapter(device: &Path) -> Option<Adapter> {
+    let type_ = attribute(device, "type")?;
+    if !(type_ == "Mains" || type_ == "Wireless" || type_.starts_with("USB")) {
+        return None;
+    }
+    let watts = micro(device, "voltage_max")
+        .zip(micro(device, "current_max"))
+        .map(|(voltage, current)| voltage * current)
+        .filter(|&watts| watts > 0.);
+    Some(Adapter {
+        name: device.file_name()?.to_string_lossy().into_owned(),
+        online: attribute(device, "online").as_deref() == Some("1"),
+        watts,
+    })
+}
+
+/// Adapters under `root`
+pub fn read_adapters(root: &Path) -> io::Result<Vec<Adapter>> {
+    let mut adapters = Vec::new();
+    for entry in fs::read_dir(root)? {
+        if let Some(adapter) = read_adapter(&entry?.path()) {
+            adapters.push(adapter);
+        }
+    }
+    adapters.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(adapters)
+}
+
+#[cfg(test)]
+mod power_supply_tests {
+    use super::*;
+
+    #[test]
+    fn reads_batteries_and_adapters() {
+        let root =
+            std::env::temp_dir().join(format!("simple-osd-power-supply-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (device, attributes) in [
+            (
+                "BAT0",
+                &[
+                    ("type", "Battery"),
+                    ("status", "Discharging"),
+                    ("energy_now", "10000000"),
+                    ("energy_full", "40000000"),
+                    ("power_now", "5000000"),
+                ][..],
+            ),
+            (
+                "BAT1",
+                &[
+                    ("type", "Battery"),
+                    ("status", "Charging"),
+                    ("manufacturer", "ACME"),
+                    ("charge_now", "1000000"),
+                    ("charge_full", "2000000"),
+                    ("voltage_min_design", "10000000"),
+                    ("current_now", "500000"),
+                    ("voltage_now", "12000000"),
+                ][..],
+            ),
+            ("AC", &[("type", "Mains"), ("online", "0")][..]),
+            (
+                "ucsi-source-psy-USBC000:001",
+                &[
+                    ("type", "USB"),
+                    ("online", "1"),
+                    ("voltage_max", "20000000"),
+                    ("current_max", "3250000"),
+                ][..],
+            ),
+        ] {
+            fs::create_dir_all(root.join(device)).unwrap();
+            for (attribute, value) in attributes {
+                fs::write(root.join(device).join(attribute), format!("{}\n", value)).unwrap();
+            }
+        }
+
+        assert_eq!(
+            read_batteries(&root).unwrap(),
+            vec![
+                (
+                    String::from("Battery 1"),
+                    Sample {
+                        energy: 36000.,
+                        energy_full: 144000.,
+                        energy_rate: 5.,
+                        state: battery::State::Discharging,
+                    }
+                ),
+                (
+                    String::from("ACME"),
+                    Sample {
+                        energy: 36000.,
+                        energy_full: 72000.,
+                        energy_rate: 6.,
+                        state: battery::State::Charging,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            read_adapters(&root).unwrap(),
+            vec![
+                Adapter {
+                    name: String::from("AC"),
+                    online: false,
+                    watts: None,
+                },
+                Adapter {
+                    name: String::from("ucsi-source-psy-USBC000:001"),
+                    online: true,
+                    watts: Some(65.),
+                },
+            ]
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+}
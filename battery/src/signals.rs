@@ -0,0 +1,39 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Following the signals of a D-Bus service from a thread of its own
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::MatchRule;
+
+/// Call `on_change` from another thread for every message matching `rule`, until it returns false
+/// or `stopped` is set; `service` names the sender in warnings
+pub fn watch<F>(
+    connection: &Connection,
+    rule: MatchRule<'static>,
+    service: &'static str,
+    stopped: Arc<AtomicBool>,
+    mut on_change: F,
+) -> zbus::Result<()>
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let messages = MessageIterator::for_match_rule(rule, connection, None)?;
+    thread::spawn(move || {
+        for message in messages {
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            match message {
+                Ok(_) if !on_change() => break,
+                Ok(_) => {}
+                Err(err) => warn!("Failed to receive a signal from {}: {}", service, err),
+            }
+        }
+    });
+    Ok(())
+}
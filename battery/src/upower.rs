@@ -0,0 +1,413 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Battery readings from UPower, which smooths the time estimations and tells when they change
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use zbus::blocking::Connection;
+use zbus::message::Type as MessageType;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{proxy, MatchRule};
+
+use crate::peripheral::{Kind, Peripheral};
+use crate::signals;
+use crate::Reading;
+
+static SERVICE: &str = "org.freedesktop.UPower";
+static DISPLAY_DEVICE: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+
+#[proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower",
+    gen_async = false,
+    blocking_name = "UPowerProxy"
+)]
+trait UPower {
+    fn enumerate_devices(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower",
+    gen_async = false,
+    blocking_name = "DeviceProxy"
+)]
+trait Device {
+    #[zbus(property, name = "Type")]
+    fn type_(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn power_supply(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn is_present(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn vendor(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn model(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn time_to_empty(&self) -> zbus::Result<i64>;
+
+    #[zbus(property)]
+    fn time_to_full(&self) -> zbus::Result<i64>;
+}
+
+/// `Type` of an AC adapter
+const TYPE_LINE_POWER: u32 = 1;
+/// `Type` of a battery
+const TYPE_BATTERY: u32 = 2;
+
+/// `State` values meaning the battery is on external power
+const STATE_CHARGING: u32 = 1;
+const STATE_FULLY_CHARGED: u32 = 4;
+const STATE_PENDING_CHARGE: u32 = 5;
+
+fn seconds(time: i64) -> Option<f32> {
+    Some(time as f32).filter(|&t| t > 0.)
+}
+
+impl DeviceProxy<'_> {
+    fn reading(&self) -> zbus::Result<Reading> {
+        let state = self.state()?;
+        let percentage = self.percentage()? as f32;
+        Ok(Reading {
+            soc: percentage as i32,
+            charge: percentage,
+            charging: matches!(
+                state,
+                STATE_CHARGING | STATE_FULLY_CHARGED | STATE_PENDING_CHARGE
+            ),
+            time_to_empty: seconds(self.time_to_empty()?),
+            time_to_full: seconds(self.time_to_full()?),
+        })
+    }
+
+    fn name(&self, fallback: impl FnOnce() -> String) -> zbus::Result<String> {
+        let name = [self.vendor()?, self.model()?]
+            .iter()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(if name.is_empty() { fallback() } else { name })
+    }
+}
+
+pub struct UPower {
+    connection: Connection,
+    /// Tells the signal watching thread to stop
+    stopped: Arc<AtomicBool>,
+}
+
+impl UPower {
+    /// Connect to UPower on the system bus
+    pub fn new() -> zbus::Result<UPower> {
+        UPower::with_connection(Connection::system()?)
+    }
+
+    pub fn with_connection(connection: Connection) -> zbus::Result<UPower> {
+        let upower = UPower {
+            connection,
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        // Fail early if UPower isn't there
+        upower.device(DISPLAY_DEVICE)?.is_present()?;
+        Ok(upower)
+    }
+
+    fn device(&self, path: &str) -> zbus::Result<DeviceProxy<'static>> {
+        DeviceProxy::builder(&self.connection)
+            .path(path.to_string())?
+            .cache_properties(CacheProperties::No)
+            .build()
+    }
+
+    /// The combined battery UPower shows in desktop panels, or each battery on its own
+    pub fn readings(&self, combine: bool) -> zbus::Result<Vec<(String, Reading)>> {
+        if combine {
+            let display = self.device(DISPLAY_DEVICE)?;
+            return Ok(if display.is_present()? {
+                vec![(String::new(), display.reading()?)]
+            } else {
+                Vec::new()
+            });
+        }
+
+        let mut index = 0;
+        self.each_device(|device| {
+            if device.type_()? != TYPE_BATTERY || !device.power_supply()? || !device.is_present()? {
+                return Ok(None);
+            }
+            index += 1;
+            let name = device.name(|| format!("Battery {}", index))?;
+            Ok(Some((name, device.reading()?)))
+        })
+    }
+
+    /// Batteries of mice, keyboards, headsets and such
+    pub fn peripherals(&self) -> zbus::Result<Vec<Peripheral>> {
+        self.each_device(|device| {
+            let type_ = device.type_()?;
+            if type_ == TYPE_LINE_POWER || device.power_supply()? || !device.is_present()? {
+                return Ok(None);
+            }
+            let kind = Kind::from_upower(type_);
+            Ok(Some(Peripheral {
+                name: device.name(|| kind.name().to_string())?,
+                kind,
+                reading: device.reading()?,
+            }))
+        })
+    }
+
+    /// What `read` makes of each device, leaving out those that are gone before they are read
+    fn each_device<T, F>(&self, mut read: F) -> zbus::Result<Vec<T>>
+    where
+        F: FnMut(&DeviceProxy) -> zbus::Result<Option<T>>,
+    {
+        let mut items = Vec::new();
+        let paths = UPowerProxy::new(&self.connection)?.enumerate_devices()?;
+        for path in paths {
+            match self.device(path.as_str()).and_then(|device| read(&device)) {
+                Ok(item) => items.extend(item),
+                Err(err) => debug!("Skipping {}: {}", path.as_str(), err),
+            }
+        }
+        Ok(items)
+    }
+
+    /// Call `on_change` from another thread whenever UPower reports a change, until it returns
+    /// false or this is dropped
+    pub fn watch<F>(&self, on_change: F) -> zbus::Result<()>
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(SERVICE)?
+            .build();
+        signals::watch(
+            &self.connection,
+            rule,
+            "UPower",
+            self.stopped.clone(),
+            on_change,
+        )
+    }
+}
+
+impl Drop for UPower {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod upower_tests {
+    use super::*;
+    use simple_osd_test_support::Bus;
+    use std::convert::TryFrom;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use zbus::interface;
+
+    struct MockDevice {
+        type_: u32,
+        power_supply: bool,
+        model: String,
+        percentage: f64,
+        state: u32,
+        time_to_empty: i64,
+    }
+
+    #[interface(name = "org.freedesktop.UPower.Device")]
+    impl MockDevice {
+        #[zbus(property, name = "Type")]
+        fn type_(&self) -> u32 {
+            self.type_
+        }
+        #[zbus(property)]
+        fn power_supply(&self) -> bool {
+            self.power_supply
+        }
+        #[zbus(property)]
+        fn is_present(&self) -> bool {
+            true
+        }
+        #[zbus(property)]
+        fn vendor(&self) -> String {
+            String::new()
+        }
+        #[zbus(property)]
+        fn model(&self) -> String {
+            self.model.clone()
+        }
+        #[zbus(property)]
+        fn percentage(&self) -> f64 {
+            self.percentage
+        }
+        #[zbus(property)]
+        fn state(&self) -> u32 {
+            self.state
+        }
+        #[zbus(property)]
+        fn time_to_empty(&self) -> i64 {
+            self.time_to_empty
+        }
+        #[zbus(property)]
+        fn time_to_full(&self) -> i64 {
+            0
+        }
+    }
+
+    struct MockUPower {
+        devices: Vec<OwnedObjectPath>,
+    }
+
+    #[interface(name = "org.freedesktop.UPower")]
+    impl MockUPower {
+        fn enumerate_devices(&self) -> Vec<OwnedObjectPath> {
+            self.devices.clone()
+        }
+    }
+
+    fn battery(model: &str, percentage: f64) -> MockDevice {
+        MockDevice {
+            type_: TYPE_BATTERY,
+            power_supply: true,
+            model: model.to_string(),
+            percentage,
+            state: 2,
+            time_to_empty: 3600,
+        }
+    }
+
+    fn path(name: &str) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(format!("/org/freedesktop/UPower/devices/{}", name)).unwrap()
+    }
+
+    /// UPower with two batteries, one that is gone, and a mouse, and a display device combining
+    /// the batteries
+    fn mock_upower(bus: &Bus) -> Connection {
+        let connection = bus.serve(
+            SERVICE,
+            "/org/freedesktop/UPower",
+            MockUPower {
+                devices: vec![
+                    path("battery_BAT0"),
+                    path("battery_BAT1"),
+                    // Unplugged after it has been listed
+                    path("battery_BAT2"),
+                    path("mouse"),
+                ],
+            },
+        );
+        {
+            let server = connection.object_server();
+            server.at(DISPLAY_DEVICE, battery("", 60.)).unwrap();
+            server
+                .at(path("battery_BAT0").as_str(), battery("Internal", 40.))
+                .unwrap();
+            server
+                .at(path("battery_BAT1").as_str(), battery("", 80.))
+                .unwrap();
+            let mut mouse = battery("Mouse", 10.);
+            mouse.type_ = 5;
+            mouse.power_supply = false;
+            server.at(path("mouse").as_str(), mouse).unwrap();
+        }
+        connection
+    }
+
+    #[test]
+    fn reads_display_device() {
+        let bus = Bus::start();
+        let _service = mock_upower(&bus);
+        let upower = UPower::with_connection(bus.connect()).unwrap();
+        assert_eq!(
+            upower.readings(true).unwrap(),
+            vec![(
+                String::new(),
+                Reading {
+                    soc: 60,
+                    charge: 60.,
+                    charging: false,
+                    time_to_empty: Some(3600.),
+                    time_to_full: None,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn reads_each_battery() {
+        let bus = Bus::start();
+        let _service = mock_upower(&bus);
+        let upower = UPower::with_connection(bus.connect()).unwrap();
+        let readings: Vec<(String, i32)> = upower
+            .readings(false)
+            .unwrap()
+            .into_iter()
+            .map(|(name, reading)| (name, reading.soc))
+            .collect();
+        assert_eq!(
+            readings,
+            vec![
+                (String::from("Internal"), 40),
+                (String::from("Battery 2"), 80)
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_peripherals() {
+        let bus = Bus::start();
+        let _service = mock_upower(&bus);
+        let upower = UPower::with_connection(bus.connect()).unwrap();
+        let peripherals = upower.peripherals().unwrap();
+        assert_eq!(peripherals.len(), 1);
+        assert_eq!(peripherals[0].name, "Mouse");
+        assert_eq!(peripherals[0].kind, Kind::Mouse);
+        assert_eq!(peripherals[0].reading.soc, 10);
+    }
+
+    #[test]
+    fn fails_without_upower() {
+        let bus = Bus::start();
+        assert!(UPower::with_connection(bus.connect()).is_err());
+    }
+
+    #[test]
+    fn property_changes_are_watched() {
+        let bus = Bus::start();
+        let service = mock_upower(&bus);
+        let upower = UPower::with_connection(bus.connect()).unwrap();
+        let (sender, receiver) = channel();
+        upower.watch(move || sender.send(()).is_ok()).unwrap();
+
+        let device = service
+            .object_server()
+            .interface::<_, MockDevice>(DISPLAY_DEVICE)
+            .unwrap();
+        device.get_mut().state = STATE_CHARGING;
+        zbus::block_on(device.get().state_changed(device.signal_emitter())).unwrap();
+
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No change reported");
+        assert!(upower.readings(true).unwrap()[0].1.charging);
+    }
+}
@@ -0,0 +1,131 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+extern crate simple_osd_common as osd;
+
+#[macro_use]
+extern crate log;
+
+use thiserror::Error;
+
+use osd::control::{Command, Control};
+use osd::daemon::{ready, Heartbeat};
+use osd::supervisor::{supervise, Backoff};
+
+use osd::notify::{OSDContents, OSD};
+
+use async_io::Timer;
+use bluer::{self, AdapterEvent};
+
+use futures_lite::stream::{self, StreamExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+#[derive(Error, Debug)]
+pub enum BluetoothError {
+    #[error("Bluer error")]
+    BluerError(#[from] bluer::Error),
+    #[error("Failed to start the async runtime: {0}")]
+    RuntimeError(#[from] std::io::Error),
+    #[error("Bluetooth adapter event stream has ended")]
+    EventStreamEnded,
+}
+
+enum Event {
+    Adapter(AdapterEvent),
+    Control(Command),
+    /// The adapter event stream has ended; the merged stream itself never does
+    AdapterGone,
+    /// Time to ping the watchdog
+    Heartbeat,
+}
+
+/// Show the names of all currently connected devices
+async fn show_connected(adapter: &bluer::Adapter, osd: &mut OSD) -> Result<(), BluetoothError> {
+    let mut names = Vec::new();
+    for addr in adapter.device_addresses().await? {
+        let device = adapter.device(addr)?;
+        if device.is_connected().await? {
+            names.push(device.name().await?.unwrap_or_else(|| addr.to_string()));
+        }
+    }
+    if names.is_empty() {
+        osd.title = Some(String::from("No Bluetooth devices connected"));
+        osd.icon = Some(String::from("network-bluetooth"));
+        osd.contents = OSDContents::Simple(None);
+    } else {
+        osd.title = Some(String::from("Connected to"));
+        osd.icon = Some(String::from("network-bluetooth-activated"));
+        osd.contents = OSDContents::Simple(Some(names.join(", ")));
+    }
+    osd.update_();
+    Ok(())
+}
+
+/// Show device (dis)connections for as long as bluetoothd and the adapter are around
+async fn watch_adapter(
+    osd: &mut OSD,
+    commands: &mut UnboundedReceiver<Command>,
+) -> Result<(), BluetoothError> {
+    let session = bluer::Session::new().await?;
+    let adapter = session.default_adapter().await?;
+    let adapter_events = adapter
+        .events()
+        .await?
+        .map(Event::Adapter)
+        .chain(stream::once(Event::AdapterGone));
+    let control_events = stream::poll_fn(|cx| commands.poll_recv(cx)).map(Event::Control);
+    let heartbeat = Heartbeat::register();
+    let beats = heartbeat
+        .period()
+        .map_or_else(Timer::never, Timer::interval)
+        .map(|_| Event::Heartbeat);
+    let mut events = adapter_events.or(control_events).or(beats);
+    ready();
+    loop {
+        match events.next().await {
+            Some(Event::Adapter(AdapterEvent::DeviceAdded(addr))) => {
+                let device = adapter.device(addr)?;
+                if device.is_connected().await? {
+                    osd.title = Some(String::from("Connected to"));
+                    osd.icon = Some(String::from("network-bluetooth-activated"));
+                    osd.contents = OSDContents::Simple(device.name().await?);
+                    debug!(device:% = addr; "Device connected");
+                    osd.update_();
+                }
+            },
+            Some(Event::Adapter(AdapterEvent::DeviceRemoved(addr))) => {
+                let device = adapter.device(addr)?;
+                osd.title = Some(String::from("Bluetooth device disconnected"));
+                osd.icon = Some(String::from("network-bluetooth"));
+                osd.contents = OSDContents::Simple(device.name().await?);
+                debug!(device:% = addr; "Device disconnected");
+                osd.update_();
+            },
+            Some(Event::Control(Command::ShowCurrent)) => show_connected(&adapter, osd).await?,
+            Some(Event::Control(Command::Reload)) => info!("Nothing to reload"),
+            Some(Event::Heartbeat) => heartbeat.beat(),
+            Some(Event::AdapterGone) | None => { return Err(BluetoothError::EventStreamEnded); },
+            _ => {},
+        }
+    }
+}
+
+pub fn bluetooth_daemon() -> Result<(), BluetoothError> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let (sender, mut commands) = unbounded_channel();
+    let control = Control::new_or_warn("bluetooth", move |command| {
+        let _ = sender.send(command);
+    });
+
+    let mut osd = OSD::new();
+    osd.control = Some(control.handle());
+
+    supervise("Bluetooth session", &mut Backoff::default(), || {
+        runtime.block_on(watch_adapter(&mut osd, &mut commands))
+    });
+
+    Ok(())
+}
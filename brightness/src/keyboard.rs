@@ -0,0 +1,132 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Keyboard backlights from `/sys/class/leds/*::kbd_backlight`, which usually only have a few
+//! levels and may be changed by the firmware itself (Fn+Space on ThinkPads)
+
+use std::io;
+use std::path::PathBuf;
+
+use osd::config::Config;
+use osd::notify::{OSDContents, OSDProgressText, OSD};
+use sysfs_class::{Brightness, Leds, SysClass};
+
+pub struct Keyboard {
+    leds: Leds,
+    max: u64,
+    /// As last seen
+    level: u64,
+    /// Title of the OSD, with `{level}` and `{max}` filled in
+    template: String,
+    osd: OSD,
+}
+
+impl Keyboard {
+    /// The keyboard backlight, if there is one
+    pub fn find() -> Option<Leds> {
+        let mut all: Vec<Leds> = Leds::iter_keyboards().filter_map(Result::ok).collect();
+        all.sort_by(|a, b| a.id().cmp(b.id()));
+        all.into_iter().next()
+    }
+
+    pub fn new(leds: Leds, config: &mut Config, mut osd: OSD) -> io::Result<Keyboard> {
+        osd.icon = Some(config.get_override("icons", "keyboard-brightness-symbolic"));
+        Ok(Keyboard {
+            max: leds.max_brightness()?,
+            level: leds.brightness()?,
+            template: config.get_default(
+                "keyboard",
+                "template",
+                String::from("Keyboard backlight {level}/{max}"),
+            ),
+            leds,
+            osd,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        self.leds.id()
+    }
+
+    /// What to watch for changes: drivers notify about `brightness_hw_changed`, when they have
+    /// it, once the firmware has changed the level
+    pub fn attributes(&self) -> Vec<PathBuf> {
+        ["brightness", "brightness_hw_changed"]
+            .iter()
+            .map(|attribute| self.leds.path().join(attribute))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    /// Show the level if it has changed since it was last seen
+    pub fn update(&mut self) -> io::Result<()> {
+        let level = self.leds.brightness()?;
+        if level != self.level {
+            debug!(device = self.id(); "Keyboard backlight level {}/{}", level, self.max);
+            self.level = level;
+            self.show();
+        }
+        Ok(())
+    }
+
+    fn show(&mut self) {
+        self.osd.title = Some(
+            self.template
+                .replace("{level}", &self.level.to_string())
+                .replace("{max}", &self.max.to_string()),
+        );
+        // The steps are in the title already
+        self.osd.contents = OSDContents::Progress(
+            self.level as f32 / self.max.max(1) as f32,
+            OSDProgressText::Text(None),
+        );
+        self.osd.update_();
+    }
+}
+
+#[cfg(test)]
+mod keyboard_tests {
+    use super::*;
+    use osd::notify::Style;
+    use simple_osd_test_support::NotificationServer;
+    use std::fs;
+
+    #[test]
+    fn shows_level_changes() {
+        let server = NotificationServer::start();
+        let root = std::env::temp_dir().join(format!("simple-osd-keyboard-{}", std::process::id()));
+        let path = root.join("tpacpi::kbd_backlight");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("max_brightness"), "2\n").unwrap();
+        fs::write(path.join("brightness"), "0\n").unwrap();
+        let leds = unsafe { Leds::from_path_unchecked(path.clone()) };
+        let osd = OSD::builder().style(Style::default()).build();
+        let mut keyboard = Keyboard::new(leds, &mut Config::empty(), osd).unwrap();
+        assert_eq!(keyboard.attributes(), vec![path.join("brightness")]);
+        fs::write(path.join("brightness_hw_changed"), "0\n").unwrap();
+        assert_eq!(
+            keyboard.attributes(),
+            vec![path.join("brightness"), path.join("brightness_hw_changed")]
+        );
+
+        keyboard.update().unwrap();
+        assert!(server.notifications().is_empty());
+        // As if the firmware has changed the level: the driver would also notify about
+        // brightness_hw_changed, but the level is read from brightness either way
+        fs::write(path.join("brightness"), "1\n").unwrap();
+        keyboard.update().unwrap();
+        keyboard.update().unwrap();
+        fs::write(path.join("brightness"), "2\n").unwrap();
+        keyboard.update().unwrap();
+
+        let notifications = server.notifications();
+        let summaries: Vec<&str> = notifications.iter().map(|n| n.summary.as_str()).collect();
+        assert_eq!(
+            summaries,
+            vec!["Keyboard backlight 1/2", "Keyboard backlight 2/2"]
+        );
+        assert_eq!(notifications[0].icon, "keyboard-brightness-symbolic");
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,436 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+extern crate simple_osd_common as osd;
+extern crate sysfs_class;
+#[macro_use]
+extern crate log;
+
+mod keyboard;
+
+use osd::control::Command;
+use osd::daemon::{Context, Daemon};
+use osd::event::TimerId;
+use osd::inotify::{Inotify, Watch};
+use osd::notify::{OSDContents, OSDProgressText, OSD};
+use osd::uevent::UeventSocket;
+use std::cmp::Reverse;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use sysfs_class::{Backlight, Brightness as _, SysClass};
+use thiserror::Error;
+
+use keyboard::Keyboard;
+
+#[derive(Error, Debug)]
+pub enum BrightnessError {
+    #[error("Failed to initialite backlight (possibly invalid backend): {0}")]
+    BacklightInitError(std::io::Error),
+    #[error("No backlight found in /sys/class/backlight")]
+    NoBacklight,
+    #[error("Failed to get maximum brightness: {0}")]
+    MaxBrightnessError(std::io::Error),
+    #[error("Failed to get brightness: {0}")]
+    BrightnessError(std::io::Error),
+    #[error("Failed to update a notification: {0}")]
+    OSDUpdate(#[from] osd::notify::UpdateError),
+    #[error("Event loop failed: {0}")]
+    EventLoop(std::io::Error),
+}
+
+/// The kernel recommends firmware interfaces over platform ones, and those over raw ones, which
+/// are often the wrong device on laptops with several
+fn priority(backlight: &Backlight) -> u8 {
+    match backlight.type_().as_deref() {
+        Ok("firmware") => 2,
+        Ok("platform") => 1,
+        _ => 0,
+    }
+}
+
+/// The backlight to follow out of `backlights`
+fn choose(mut backlights: Vec<Backlight>) -> Option<Backlight> {
+    backlights.sort_by(|a, b| a.id().cmp(b.id()));
+    backlights
+        .into_iter()
+        .min_by_key(|backlight| Reverse(priority(backlight)))
+}
+
+/// Names of the backlights there are now, to tell when one is plugged in or out
+fn backlight_ids() -> Vec<String> {
+    let mut ids: Vec<String> = Backlight::iter()
+        .filter_map(|backlight| Some(backlight.ok()?.id().to_string()))
+        .collect();
+    ids.sort();
+    ids
+}
+
+/// The backlight named by `backend`, which may also be a path ending with its name (like
+/// `/var/lib/backlight/intel_backlight`, where systemd keeps its brightness), or the one
+/// `choose` picks if `backend` is `auto`
+fn open(backend: &str) -> Result<Backlight, BrightnessError> {
+    if backend != "auto" {
+        let name = Path::new(backend)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(backend);
+        match Backlight::new(name) {
+            Ok(backlight) => return Ok(backlight),
+            Err(err) => warn!(
+                "Backlight {} is not available ({}), looking for another one",
+                backend, err
+            ),
+        }
+    }
+    let all = Backlight::all().map_err(BrightnessError::BacklightInitError)?;
+    choose(all).ok_or(BrightnessError::NoBacklight)
+}
+
+/// How changes of the brightness are noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Detection {
+    /// Through inotify, which needs the driver to notify about changes it makes itself
+    Inotify,
+    /// By reading the brightness every `refresh interval`
+    Poll,
+}
+
+impl FromStr for Detection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inotify" => Ok(Detection::Inotify),
+            "poll" => Ok(Detection::Poll),
+            _ => Err(format!(
+                "Unknown change detection {}, must be inotify or poll",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Detection::Inotify => "inotify",
+            Detection::Poll => "poll",
+        })
+    }
+}
+
+/// How often the brightness is read even though inotify is watching it, to notice drivers that
+/// don't notify about changes, and backlights plugged in without uevents
+const SAFETY_POLL: Duration = Duration::from_secs(5);
+
+/// Whether the keyboard backlight to follow isn't `keyboard` anymore
+fn keyboard_replaced(keyboard: Option<&Keyboard>) -> bool {
+    Keyboard::find().map(|leds| leds.id().to_string())
+        != keyboard.map(|keyboard| keyboard.id().to_string())
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Refresh,
+    /// A watched brightness attribute has been modified
+    Changed,
+    /// The kernel has sent uevents
+    Uevent,
+}
+
+pub struct Brightness {
+    inotify: Option<Rc<Inotify>>,
+    uevents: Option<Rc<UeventSocket>>,
+    refresh: TimerId,
+    watches: Vec<Watch>,
+    osd: OSD,
+    /// Kept across reloads and replugs, which don't change the brightness by themselves
+    last_b: f32,
+    /// Names of the backlights there were when `backlight` was picked
+    ids: Vec<String>,
+    /// With its maximum brightness; `None` until one is plugged in
+    backlight: Option<(Backlight, f32)>,
+    keyboard: Option<Keyboard>,
+    /// Whether inotify watches the brightness, rather than it being polled
+    watched: bool,
+    refresh_interval: Duration,
+    /// Whether the brightness is read because of the refresh timer, rather than a notification
+    refreshed: bool,
+}
+
+impl Brightness {
+    /// Pick the backlight and keyboard to follow, according to the configuration
+    fn follow(&mut self, ctx: &mut Context<Event>) -> Result<(), BrightnessError> {
+        let config = ctx.config();
+
+        let refresh_interval = config.get_default("default", "refresh interval", 500);
+        let detection = config.get_default("default", "change detection", Detection::Inotify);
+
+        let backend = config.get_default("default", "backlight backend", String::from("auto"));
+
+        self.ids = backlight_ids();
+        let backlight = match open(&backend) {
+            Ok(backlight) => Some(backlight),
+            // Such as on a desktop, or once the external monitor that had it is unplugged
+            Err(BrightnessError::NoBacklight) => {
+                info!("No screen backlight, waiting for one to be plugged in");
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        self.backlight = match backlight {
+            Some(backlight) => {
+                let m = backlight
+                    .max_brightness()
+                    .map(|b| b as f32)
+                    .map_err(BrightnessError::MaxBrightnessError)?;
+                info!(device = backlight.id(); "Following backlight");
+                debug!(device = backlight.id(); "Maximum brightness: {0}", m);
+                Some((backlight, m))
+            }
+            None => None,
+        };
+
+        let osd = ctx.osd().build();
+        let config = ctx.config();
+        self.keyboard = Keyboard::find()
+            .filter(|_| config.get_default("keyboard", "enabled", true))
+            .and_then(|leds| {
+                Keyboard::new(leds, config, osd)
+                    .map_err(|err| warn!("Unable to read the keyboard backlight: {}", err))
+                    .ok()
+            });
+        if let Some(keyboard) = &self.keyboard {
+            info!(device = keyboard.id(); "Following keyboard backlight");
+        }
+
+        // Drivers notify about `actual_brightness` when they change the brightness themselves
+        let mut attributes: Vec<PathBuf> = self
+            .backlight
+            .iter()
+            .flat_map(|(backlight, _)| {
+                ["brightness", "actual_brightness"]
+                    .iter()
+                    .map(move |attribute| backlight.path().join(attribute))
+            })
+            .collect();
+        attributes.extend(self.keyboard.iter().flat_map(Keyboard::attributes));
+        if let Some(inotify) = &self.inotify {
+            for watch in self.watches.drain(..) {
+                inotify.unwatch(watch);
+            }
+        }
+        self.watched = match (&self.inotify, detection) {
+            (Some(inotify), Detection::Inotify) => attributes
+                .iter()
+                .map(|attribute| inotify.watch(attribute))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|added| self.watches = added)
+                .map_err(|err| warn!("Unable to watch, polling instead: {}", err))
+                .is_ok(),
+            _ => false,
+        };
+        self.refresh_interval = Duration::from_millis(refresh_interval);
+        let interval = if self.watched {
+            SAFETY_POLL
+        } else {
+            self.refresh_interval
+        };
+        ctx.set_timer(self.refresh, Some(interval));
+        Ok(())
+    }
+
+    /// Show the brightness if it has changed since it was last seen, or if `force` is set
+    fn update(&mut self, ctx: &mut Context<Event>, force: bool) -> Result<(), BrightnessError> {
+        if let Some((backlight, m)) = &self.backlight {
+            let (device, m) = (backlight.id(), *m);
+            let b = match backlight.brightness() {
+                Ok(b) => b as f32,
+                // Unplugged, such as the backlight of an external monitor
+                Err(_) if !backlight.path().exists() => {
+                    info!(device = device; "Backlight is gone");
+                    return self.follow(ctx);
+                }
+                Err(err) => return Err(BrightnessError::BrightnessError(err)),
+            };
+
+            if self.watched && self.refreshed && (b - self.last_b).abs() > 0.1 {
+                // Unless the notification is on its way
+                let notified = self
+                    .inotify
+                    .as_ref()
+                    .and_then(|inotify| inotify.receive().ok())
+                    .is_some_and(|received| !received.is_empty());
+                if !notified {
+                    warn!(
+                        device = device;
+                        "The driver doesn't notify about brightness changes, polling instead"
+                    );
+                    self.watched = false;
+                    ctx.set_timer(self.refresh, Some(self.refresh_interval));
+                }
+            }
+
+            if (b - self.last_b).abs() > 0.1 || force {
+                self.osd.icon = Some(String::from(if b / m < 0.33 {
+                    "display-brightness-low"
+                } else if b / m < 0.66 {
+                    "display-brightness-medium"
+                } else {
+                    "display-brightness-high"
+                }));
+                self.osd.contents = OSDContents::Progress(b / m, OSDProgressText::Percentage);
+                self.osd.update_();
+            }
+
+            self.last_b = b;
+        }
+
+        if let Some(Err(err)) = self.keyboard.as_mut().map(Keyboard::update) {
+            warn!("Unable to read the keyboard backlight: {}", err);
+            self.keyboard = None;
+        }
+        Ok(())
+    }
+}
+
+impl Daemon for Brightness {
+    const NAME: &'static str = "brightness";
+
+    type Event = Event;
+    type Error = BrightnessError;
+
+    fn init(ctx: &mut Context<Event>) -> Result<Brightness, BrightnessError> {
+        let inotify = Inotify::new()
+            .map_err(|err| warn!("Unable to use inotify, polling instead: {}", err))
+            .ok()
+            .map(Rc::new);
+        let uevents = UeventSocket::open()
+            .map_err(|err| warn!("Unable to listen to uevents: {}", err))
+            .ok()
+            .map(Rc::new);
+
+        if let Some(inotify) = &inotify {
+            ctx.add_fd(inotify.clone(), Event::Changed)
+                .map_err(BrightnessError::EventLoop)?;
+        }
+        if let Some(uevents) = &uevents {
+            ctx.add_fd(uevents.clone(), Event::Uevent)
+                .map_err(BrightnessError::EventLoop)?;
+        }
+
+        let mut brightness = Brightness {
+            inotify,
+            uevents,
+            refresh: ctx.add_timer(Duration::from_millis(500), Event::Refresh),
+            watches: Vec::new(),
+            osd: ctx.osd().title("Screen brightness").build(),
+            last_b: 0.,
+            ids: Vec::new(),
+            backlight: None,
+            keyboard: None,
+            watched: false,
+            refresh_interval: Duration::from_millis(500),
+            refreshed: false,
+        };
+        brightness.follow(ctx)?;
+        brightness.update(ctx, false)?;
+        Ok(brightness)
+    }
+
+    fn on_event(&mut self, ctx: &mut Context<Event>, event: Event) -> Result<(), BrightnessError> {
+        self.refreshed = matches!(event, Event::Refresh);
+        match event {
+            // Switch to a backlight that has been plugged in, if it is preferred
+            Event::Refresh if backlight_ids() != self.ids => self.follow(ctx)?,
+            Event::Refresh
+                if self.uevents.is_none() && keyboard_replaced(self.keyboard.as_ref()) =>
+            {
+                self.follow(ctx)?
+            }
+            Event::Refresh => {}
+            Event::Changed => {
+                if let Some(inotify) = &self.inotify {
+                    inotify.receive().map_err(BrightnessError::EventLoop)?;
+                }
+            }
+            Event::Uevent => {
+                let received = match &self.uevents {
+                    Some(uevents) => uevents.receive().map_err(BrightnessError::EventLoop)?,
+                    None => Vec::new(),
+                };
+                let plugged = |subsystem| {
+                    received.iter().any(|uevent| {
+                        uevent.subsystem() == Some(subsystem) && uevent.action != "change"
+                    })
+                };
+                // Such as that of an external keyboard
+                if (plugged("backlight") && backlight_ids() != self.ids)
+                    || (plugged("leds") && keyboard_replaced(self.keyboard.as_ref()))
+                {
+                    self.follow(ctx)?;
+                }
+            }
+        }
+        self.update(ctx, false)
+    }
+
+    fn on_command(
+        &mut self,
+        ctx: &mut Context<Event>,
+        command: Command,
+    ) -> Result<(), BrightnessError> {
+        match command {
+            Command::ShowCurrent => self.update(ctx, true),
+            Command::Reload => {
+                ctx.reload_config();
+                self.follow(ctx)?;
+                self.update(ctx, false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod backlight_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn prefers_firmware_then_platform() {
+        let root =
+            std::env::temp_dir().join(format!("simple-osd-backlight-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let backlight = |id: &str, type_: &str| {
+            let path = root.join(id);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("type"), format!("{}\n", type_)).unwrap();
+            unsafe { Backlight::from_path_unchecked(path) }
+        };
+
+        let raw = backlight("intel_backlight", "raw");
+        let platform = backlight("thinkpad_screen", "platform");
+        let firmware = backlight("acpi_video0", "firmware");
+        let other_raw = backlight("amdgpu_bl0", "raw");
+        let chosen = |backlights: Vec<&Backlight>| {
+            choose(backlights.into_iter().cloned().collect()).map(|b| b.id().to_string())
+        };
+        assert_eq!(
+            chosen(vec![&raw, &platform, &firmware]).as_deref(),
+            Some("acpi_video0")
+        );
+        assert_eq!(
+            chosen(vec![&raw, &platform]).as_deref(),
+            Some("thinkpad_screen")
+        );
+        assert_eq!(
+            chosen(vec![&raw, &other_raw]).as_deref(),
+            Some("amdgpu_bl0")
+        );
+        assert_eq!(chosen(vec![]), None);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
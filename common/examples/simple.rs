@@ -6,10 +6,9 @@ extern crate thiserror;
 #[macro_use]
 extern crate log;
 
-use osd::config::Config;
-use osd::daemon::run;
+use osd::control::Command;
+use osd::daemon::{run_daemon, Context, Daemon};
 use osd::notify::{OSDContents, OSDProgressText, Urgency, OSD};
-use std::thread::sleep;
 use std::time::Duration;
 use thiserror::Error;
 
@@ -17,69 +16,110 @@ use thiserror::Error;
 enum SimpleError {
     #[error("Failed to update a notification: {0}")]
     OSDUpdate(#[from] osd::notify::UpdateError),
-    #[error("Failed to close a notification: {0}")]
-    OSDClose(#[from] osd::notify::CloseError),
-    #[error("Failed to set a notification close callback: {0}")]
-    OSDOnClose(#[from] osd::notify::CloseCallbackError),
 }
 
-fn simple_daemon() -> Result<(), SimpleError> {
-    let mut config = Config::new("simple-example");
+#[derive(Clone, Debug)]
+enum Event {
+    Tick,
+}
+
+struct Simple {
+    refresh_interval: u64,
+    percentage: f32,
+    elapsed: f32,
+    osd_simple: OSD,
+    osd_progress_bar_percentage: OSD,
+    osd_progress_bar_text: OSD,
+}
 
-    let foo = config.get_default("example section", "foo", "bar baz".to_string());
+const ETA: f32 = 15.;
 
-    info!("Value of foo is {}", foo);
+impl Daemon for Simple {
+    const NAME: &'static str = "simple-example";
 
-    let example_no_default =
-        config.get::<i32>("example section", "example variable with no default");
+    type Event = Event;
+    type Error = SimpleError;
 
-    info!(
-        "Value of example variable with no default is {:?}",
-        example_no_default
-    );
+    fn init(ctx: &mut Context<Event>) -> Result<Simple, SimpleError> {
+        let config = ctx.config();
 
-    let refresh_interval = config.get_default("default", "refresh interval", 1);
+        let greeting = config.get_default("example section", "greeting", "hello world".to_string());
 
-    let mut osd_simple = OSD::new();
-    osd_simple.title = Some("Simple (but urgent) notification".to_string());
-    osd_simple.contents = OSDContents::Simple(Some("Just simple contents".to_string()));
-    osd_simple.urgency = Urgency::Critical;
+        info!("Value of greeting is {}", greeting);
 
-    let mut percentage = 0.;
+        let example_no_default =
+            config.get::<i32>("example section", "example variable with no default");
 
-    let mut osd_progress_bar_percentage = OSD::new();
-    osd_progress_bar_percentage.title =
-        Some("A progress bar showing important percentage!".to_string());
+        info!(
+            "Value of example variable with no default is {:?}",
+            example_no_default
+        );
 
-    let eta = 15.;
-    let mut elapsed = 0.;
+        let refresh_interval = config.get_default("default", "refresh interval", 1);
+
+        ctx.add_timer(Duration::from_secs(refresh_interval), Event::Tick);
+
+        Ok(Simple {
+            refresh_interval,
+            percentage: 0.,
+            elapsed: 0.,
+            osd_simple: ctx
+                .osd()
+                .title("Simple (but urgent) notification")
+                .contents(OSDContents::Simple(Some("Just simple contents".to_string())))
+                .urgency(Urgency::Critical)
+                .build(),
+            osd_progress_bar_percentage: ctx
+                .osd()
+                .title("A progress bar showing important percentage!")
+                .build(),
+            osd_progress_bar_text: ctx
+                .osd()
+                .title("Nuclear warhead launch in progress, time left:")
+                .urgency(Urgency::Low)
+                .build(),
+        })
+    }
 
-    let mut osd_progress_bar_text = OSD::new();
-    osd_progress_bar_text.title =
-        Some("Nuclear warhead launch in progress, time left:".to_string());
-    osd_progress_bar_text.urgency = Urgency::Low;
+    fn on_event(&mut self, _ctx: &mut Context<Event>, event: Event) -> Result<(), SimpleError> {
+        match event {
+            Event::Tick => {
+                self.percentage = (self.percentage + 0.123) % 1.;
 
-    loop {
-        percentage = (percentage + 0.123) % 1.;
+                self.elapsed = (self.elapsed + self.refresh_interval as f32) % ETA;
 
-        elapsed = (elapsed + refresh_interval as f32) % eta;
+                self.osd_progress_bar_percentage.contents =
+                    OSDContents::Progress(self.percentage, OSDProgressText::Percentage);
 
-        osd_progress_bar_percentage.contents =
-            OSDContents::Progress(percentage, OSDProgressText::Percentage);
+                self.osd_progress_bar_text.contents = OSDContents::Progress(
+                    self.elapsed / ETA,
+                    OSDProgressText::Text(Some(format!("{}s / {}s", self.elapsed, ETA))),
+                );
 
-        osd_progress_bar_text.contents = OSDContents::Progress(
-            elapsed / eta,
-            OSDProgressText::Text(Some(format!("{}s / {}s", elapsed, eta))),
-        );
+                self.osd_simple.update()?;
+                self.osd_progress_bar_percentage.update()?;
+                self.osd_progress_bar_text.update()?;
+            }
+        }
+        Ok(())
+    }
 
-        osd_simple.update()?;
-        osd_progress_bar_percentage.update()?;
-        osd_progress_bar_text.update()?;
+    fn on_command(&mut self, ctx: &mut Context<Event>, command: Command) -> Result<(), SimpleError> {
+        match command {
+            Command::ShowCurrent => self.on_event(ctx, Event::Tick),
+            Command::Reload => {
+                ctx.reload_config();
+                Ok(())
+            }
+        }
+    }
 
-        sleep(Duration::from_secs(refresh_interval));
+    fn shutdown(&mut self, _ctx: &mut Context<Event>) -> Result<(), SimpleError> {
+        info!("Stopped at {}%", (self.percentage * 100.) as i32);
+        Ok(())
     }
 }
 
 fn main() {
-    run("simple-osd-simple", simple_daemon)
+    run_daemon::<Simple>()
 }
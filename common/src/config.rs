@@ -13,7 +13,7 @@ pub struct Config {
 }
 
 impl Config {
-    fn get_config_path(name: &'static str, config: &mut Ini) -> Option<String> {
+    fn get_config_path(name: &str, config: &mut Ini) -> Option<String> {
         BaseDirectories::with_prefix(crate::APPNAME)
             .map_err(|err| warn!("Failed to set up XDG Base Directories: {0:?}", err))
             .ok()
@@ -43,7 +43,7 @@ impl Config {
             })
     }
 
-    pub fn new(name: &'static str) -> Config {
+    pub fn new(name: &str) -> Config {
         let mut config = Ini::new();
 
         let config_path = Self::get_config_path(name, &mut config);
@@ -54,6 +54,15 @@ impl Config {
         }
     }
 
+    /// A configuration that only ever returns the defaults and is never written to disk, e.g. for
+    /// tests
+    pub fn empty() -> Config {
+        Config {
+            config: Ini::new(),
+            config_path: None,
+        }
+    }
+
     pub fn get<T>(&mut self, section: &str, key: &str) -> Option<T>
     where
         T: FromStr,
@@ -0,0 +1,315 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! `org.simpleosd.<Daemon>` control objects on the session bus.
+//!
+//! Every daemon exports an object implementing `org.simpleosd.Daemon` at
+//! `/org/simpleosd/<Daemon>`, so that keybindings and scripts can ask it to show the current
+//! state, stay quiet for a while or re-read its configuration.
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use zbus::blocking::Connection;
+use zbus::names::BusName;
+use zbus::proxy::CacheProperties;
+use zbus::{interface, proxy};
+
+/// Requests that the daemon itself has to act on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Show an OSD with the current state, even if nothing has changed
+    ShowCurrent,
+    /// Re-read the configuration
+    Reload,
+}
+
+#[derive(Default)]
+struct Shared {
+    last_state: Mutex<String>,
+    suppressed_until: Mutex<Option<Instant>>,
+    showing: Mutex<bool>,
+}
+
+/// Cheap handle to the state shared between a daemon and its control object
+#[derive(Clone, Default)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    /// Whether OSDs are currently suppressed with `Suppress()`
+    pub fn suppressed(&self) -> bool {
+        let mut until = self.shared.suppressed_until.lock().unwrap();
+        match *until {
+            Some(t) if t > Instant::now() => true,
+            Some(_) => {
+                *until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn suppress(&self, duration: Duration) {
+        *self.shared.suppressed_until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    /// Record what the daemon has shown last, exposed as the `LastState` property
+    pub fn set_last_state(&self, state: String) {
+        *self.shared.last_state.lock().unwrap() = state;
+    }
+
+    pub fn last_state(&self) -> String {
+        self.shared.last_state.lock().unwrap().clone()
+    }
+
+    /// Record whether an OSD of the daemon is on screen, see [`is_showing`]
+    pub fn set_showing(&self, showing: bool) {
+        *self.shared.showing.lock().unwrap() = showing;
+    }
+
+    pub fn showing(&self) -> bool {
+        *self.shared.showing.lock().unwrap()
+    }
+}
+
+/// Handles of the control objects of this process, so that daemons sharing it can see what the
+/// others show
+static HANDLES: Mutex<Vec<(String, Handle)>> = Mutex::new(Vec::new());
+
+/// Whether `daemon` (e.g. `pulseaudio`) runs in this process and has an OSD on screen
+pub fn is_showing(daemon: &str) -> bool {
+    HANDLES
+        .lock()
+        .map(|handles| {
+            handles
+                .iter()
+                .any(|(name, handle)| name == daemon && handle.showing())
+        })
+        .unwrap_or(false)
+}
+
+struct Interface {
+    handle: Handle,
+    on_command: Box<dyn Fn(Command) + Send + Sync>,
+}
+
+#[interface(name = "org.simpleosd.Daemon")]
+impl Interface {
+    fn show_current(&self) {
+        (self.on_command)(Command::ShowCurrent)
+    }
+
+    fn suppress(&self, seconds: u32) {
+        debug!("Suppressing OSDs for {}s", seconds);
+        self.handle.suppress(Duration::from_secs(seconds.into()))
+    }
+
+    fn reload(&self) {
+        (self.on_command)(Command::Reload)
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn last_state(&self) -> String {
+        self.handle.last_state()
+    }
+}
+
+/// Client side of the control object, used by `simple-osd-ctl`
+#[proxy(
+    interface = "org.simpleosd.Daemon",
+    gen_async = false,
+    blocking_name = "DaemonProxy"
+)]
+pub trait Daemon {
+    fn show_current(&self) -> zbus::Result<()>;
+
+    fn suppress(&self, seconds: u32) -> zbus::Result<()>;
+
+    fn reload(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn last_state(&self) -> zbus::Result<String>;
+}
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("Failed to connect to the session bus: {0}")]
+    Connection(zbus::Error),
+    #[error("Failed to export the control object: {0}")]
+    Export(zbus::Error),
+    #[error("Failed to acquire the bus name {0}: {1}")]
+    Name(String, zbus::Error),
+    #[error("Failed to reach {0}: {1}")]
+    Proxy(String, zbus::Error),
+}
+
+/// One session bus connection per process, shared by all daemons running in it
+fn connection() -> Result<&'static Connection, ControlError> {
+    static CONNECTION: OnceLock<Connection> = OnceLock::new();
+    if let Some(connection) = CONNECTION.get() {
+        return Ok(connection);
+    }
+    let connection = Connection::session().map_err(ControlError::Connection)?;
+    Ok(CONNECTION.get_or_init(|| connection))
+}
+
+/// `battery` -> `Battery`, `vpn-status` -> `Vpn_status`, so that it fits bus names and object
+/// paths alike
+fn capitalize(daemon: &str) -> String {
+    let mut chars = daemon
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' });
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// Well-known bus name of a daemon's control object, e.g. `org.simpleosd.Battery`
+pub fn bus_name(daemon: &str) -> String {
+    format!("org.simpleosd.{}", capitalize(daemon))
+}
+
+/// Object path of a daemon's control object, e.g. `/org/simpleosd/Battery`
+pub fn object_path(daemon: &str) -> String {
+    format!("/org/simpleosd/{}", capitalize(daemon))
+}
+
+/// Whether the control object of `daemon` is on the bus, i.e. the daemon is running
+pub fn is_exported(daemon: &str) -> Result<bool, ControlError> {
+    let name = bus_name(daemon);
+    let dbus = zbus::blocking::fdo::DBusProxy::new(connection()?)
+        .map_err(|err| ControlError::Proxy(name.clone(), err))?;
+    let bus_name = BusName::try_from(name.as_str())
+        .map_err(|err| ControlError::Proxy(name.clone(), err.into()))?;
+    dbus.name_has_owner(bus_name)
+        .map_err(|err| ControlError::Proxy(name, err.into()))
+}
+
+/// Whether the session bus can start `daemon` when it is called, see `--generate-units`
+pub fn is_activatable(daemon: &str) -> Result<bool, ControlError> {
+    let name = bus_name(daemon);
+    let dbus = zbus::blocking::fdo::DBusProxy::new(connection()?)
+        .map_err(|err| ControlError::Proxy(name.clone(), err))?;
+    let names = dbus
+        .list_activatable_names()
+        .map_err(|err| ControlError::Proxy(name.clone(), err.into()))?;
+    Ok(names.iter().any(|n| n.as_str() == name))
+}
+
+/// A proxy for the control object of a running (or activatable) `daemon`
+pub fn proxy(daemon: &str) -> Result<DaemonProxy<'static>, ControlError> {
+    let name = bus_name(daemon);
+    DaemonProxy::builder(connection()?)
+        .destination(name.clone())
+        .and_then(|builder| builder.path(object_path(daemon)))
+        .and_then(|builder| builder.cache_properties(CacheProperties::No).build())
+        .map_err(|err| ControlError::Proxy(name, err))
+}
+
+/// A daemon's control object; it is removed from the bus when dropped
+pub struct Control {
+    daemon: String,
+    handle: Handle,
+    exported: bool,
+}
+
+impl Control {
+    /// Export the control object for `daemon` (e.g. `battery`); `on_command` is called from
+    /// the D-Bus thread, so it should just pass the command on to the daemon's loop.
+    pub fn new<F>(daemon: &str, on_command: F) -> Result<Control, ControlError>
+    where
+        F: Fn(Command) + Send + Sync + 'static,
+    {
+        let handle = Handle::default();
+        let connection = connection()?;
+
+        connection
+            .object_server()
+            .at(
+                object_path(daemon),
+                Interface {
+                    handle: handle.clone(),
+                    on_command: Box::new(on_command),
+                },
+            )
+            .map_err(ControlError::Export)?;
+
+        let name = bus_name(daemon);
+        connection
+            .request_name(name.as_str())
+            .map_err(|err| ControlError::Name(name.clone(), err))?;
+        debug!("Exported the control object as {}", name);
+
+        Ok(Control::register(daemon, handle, true))
+    }
+
+    /// Like [`Control::new`], but keep working without a control object if the session bus is
+    /// unavailable
+    pub fn new_or_warn<F>(daemon: &str, on_command: F) -> Control
+    where
+        F: Fn(Command) + Send + Sync + 'static,
+    {
+        Control::new(daemon, on_command).unwrap_or_else(|err| {
+            warn!("{}", err);
+            Control::register(daemon, Handle::default(), false)
+        })
+    }
+
+    fn register(daemon: &str, handle: Handle, exported: bool) -> Control {
+        if let Ok(mut handles) = HANDLES.lock() {
+            handles.push((daemon.to_string(), handle.clone()));
+        }
+        Control {
+            daemon: daemon.to_string(),
+            handle,
+            exported,
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        if let Ok(mut handles) = HANDLES.lock() {
+            handles.retain(|(_, handle)| !Arc::ptr_eq(&handle.shared, &self.handle.shared));
+        }
+        if !self.exported {
+            return;
+        }
+        if let Ok(connection) = connection() {
+            let _ = connection.release_name(bus_name(&self.daemon));
+            let _ = connection
+                .object_server()
+                .remove::<Interface, _>(object_path(&self.daemon));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(bus_name("battery"), "org.simpleosd.Battery");
+        assert_eq!(object_path("pulseaudio"), "/org/simpleosd/Pulseaudio");
+        assert_eq!(object_path("vpn-status"), "/org/simpleosd/Vpn_status");
+    }
+
+    #[test]
+    fn suppression_expires() {
+        let handle = Handle::default();
+        assert!(!handle.suppressed());
+        handle.suppress(Duration::from_millis(20));
+        assert!(handle.suppressed());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!handle.suppressed());
+    }
+}
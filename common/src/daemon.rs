@@ -1,20 +1,729 @@
-use std::fmt::Display;
+use std::env;
+use std::fmt::{Debug, Display};
+use std::fs::{create_dir_all, write};
+use std::io;
 use std::ops::FnOnce;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::panic::{self, AssertUnwindSafe};
 use std::process::exit;
-pub fn run<F, E>(daemon: &str, f: F)
-where
-    F: FnOnce() -> Result<(), E>,
-    E: Display,
-{
-    pretty_env_logger::init();
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::control::{Command, Control};
+use crate::event::{EventLoop, EventSender, TimerId};
+use crate::instance::{self, InstanceError, InstanceLock};
+use crate::notify::{OSDBuilder, OSD};
+
+/// Every daemon shipped in this repository, as in `simple-osd-$DAEMON`
+pub static DAEMONS: &[&str] = &["battery", "brightness", "mpris", "pulseaudio", "bluetooth"];
+
+/// Write `simple-osd-*.service` user units for every daemon installed next to this one, and
+/// D-Bus activation files so that the control objects start them on demand
+fn generate_units(daemon: &str, dir: Option<String>) -> Result<(), String> {
+    let xdg = xdg::BaseDirectories::new()
+        .map_err(|err| format!("Failed to set up XDG Base Directories: {}", err))?;
+    let dir = match dir {
+        Some(dir) => PathBuf::from(dir),
+        None => xdg.get_config_home().join("systemd/user"),
+    };
+    let dbus_dir = xdg.get_data_home().join("dbus-1/services");
+
+    let exe = env::current_exe().map_err(|err| format!("Failed to find own executable: {}", err))?;
+    let bin_dir = exe.parent().map(PathBuf::from).unwrap_or_default();
+
+    for dir in [&dir, &dbus_dir] {
+        create_dir_all(dir).map_err(|err| format!("Failed to create {:?}: {}", dir, err))?;
+    }
+    let write_file = |path: PathBuf, contents: String| {
+        write(&path, contents).map_err(|err| format!("Failed to write {:?}: {}", path, err))?;
+        println!("{}", path.display());
+        Ok::<_, String>(())
+    };
+
+    // A multiplexing binary replaces the individual daemons rather than running next to them
+    let multiplexed = !DAEMONS.contains(&short_name(daemon));
+    let daemons = DAEMONS
+        .iter()
+        .map(|name| format!("{}-{}", crate::APPNAME, name))
+        .filter(|name| !multiplexed && name != daemon && bin_dir.join(name).is_file())
+        .chain(std::iter::once(daemon.to_string()));
+
+    for name in daemons {
+        let exec = if name == daemon { exe.clone() } else { bin_dir.join(&name) };
+        let exec = exec.to_string_lossy();
+        write_file(
+            dir.join(format!("{}.service", name)),
+            unit(short_name(&name), &exec),
+        )?;
+        let served: Vec<&str> = if multiplexed {
+            DAEMONS.to_vec()
+        } else {
+            vec![short_name(&name)]
+        };
+        for served in served {
+            let bus_name = crate::control::bus_name(served);
+            write_file(
+                dbus_dir.join(format!("{}.service", bus_name)),
+                dbus_service(&bus_name, &name, &exec),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Render a D-Bus activation file starting the unit `unit` (or running `exec` without systemd)
+/// when `bus_name` is called
+fn dbus_service(bus_name: &str, unit: &str, exec: &str) -> String {
+    format!(
+        "[D-BUS Service]
+Name={bus_name}
+Exec={exec}
+SystemdService={unit}.service
+",
+        bus_name = bus_name,
+        unit = unit,
+        exec = exec
+    )
+}
+
+/// Render a systemd user unit running `exec` as the daemon `name`
+fn unit(name: &str, exec: &str) -> String {
+    // Event loops ping the watchdog while they keep running, see `Heartbeat`
+    let (service_type, watchdog) = if cfg!(feature = "systemd") {
+        ("notify", "WatchdogSec=30\n")
+    } else {
+        ("simple", "")
+    };
+    format!(
+        "[Unit]
+Description=Simple OSD daemon ({name})
+Documentation=https://github.com/balsoft/simple-osd-daemons
+PartOf=graphical-session.target
+After=graphical-session.target
+
+[Service]
+Type={service_type}
+ExecStart={exec}
+Restart=on-failure
+RestartSec=3
+{watchdog}
+[Install]
+WantedBy=graphical-session.target
+",
+        name = name,
+        service_type = service_type,
+        exec = exec,
+        watchdog = watchdog
+    )
+}
+
+/// Daemons of a multiplexer that have yet to report that they are ready, by the names of their
+/// threads
+static STARTING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Take `name` off `starting`, and tell whether nobody is left to wait for
+fn done_starting(starting: &mut Vec<String>, name: Option<&str>) -> bool {
+    if let Some(name) = name {
+        starting.retain(|n| n != name);
+    }
+    starting.is_empty()
+}
+
+/// Tell the service manager (if any) that the daemon has finished starting up.
+///
+/// Inside [`run_many`], the process is only reported as ready once every daemon has called this
+/// (or stopped).
+pub fn ready() {
+    let all_ready = STARTING
+        .lock()
+        .map(|mut starting| done_starting(&mut starting, thread::current().name()))
+        .unwrap_or(true);
+    if all_ready {
+        #[cfg(feature = "systemd")]
+        crate::systemd::ready();
+    }
+}
+
+/// Report a human-readable status to the service manager (if any)
+pub fn status(_status: &str) {
+    #[cfg(feature = "systemd")]
+    crate::systemd::status(_status);
+}
+
+/// Keeps the service watchdog (if any) pinged for as long as the loop holding it beats.
+///
+/// [`EventLoop`] beats on its own; loops of other kinds wake up at least every
+/// [`Heartbeat::period`] and call [`Heartbeat::beat`]. Every registered loop of the process has
+/// to beat for the watchdog to be pinged, so a hung daemon gets restarted.
+pub struct Heartbeat {
+    period: Option<Duration>,
+    #[cfg(feature = "systemd")]
+    inner: Option<crate::systemd::Heartbeat>,
+}
+
+impl Heartbeat {
+    pub fn register() -> Heartbeat {
+        #[cfg(feature = "systemd")]
+        let inner = crate::systemd::Heartbeat::register();
+        Heartbeat {
+            #[cfg(feature = "systemd")]
+            period: inner.as_ref().map(|inner| inner.period()),
+            #[cfg(not(feature = "systemd"))]
+            period: None,
+            #[cfg(feature = "systemd")]
+            inner,
+        }
+    }
+
+    /// How often to beat, or `None` if there is no watchdog
+    pub fn period(&self) -> Option<Duration> {
+        self.period
+    }
+
+    pub fn beat(&self) {
+        #[cfg(feature = "systemd")]
+        if let Some(inner) = &self.inner {
+            inner.beat();
+        }
+    }
+
+    /// Sleep for `duration`, beating along the way
+    pub fn sleep(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.beat();
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(self.period.map_or(left, |period| period.min(left)));
+        }
+    }
+}
+
+/// What to do when another instance of a daemon is already running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IfRunning {
+    Fail,
+    Exit,
+    Replace,
+}
+
+struct Args {
+    positional: Vec<String>,
+    if_running: IfRunning,
+}
+
+/// Handle the common command line arguments
+fn parse_args(daemon: &str, usage: &str) -> Args {
+    let mut args = env::args().skip(1);
+    let mut parsed = Args {
+        positional: Vec::new(),
+        if_running: IfRunning::Fail,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--generate-units" => {
+                if let Err(err) = generate_units(daemon, args.next()) {
+                    eprintln!("{}", err);
+                    exit(1)
+                }
+                exit(0)
+            }
+            "--replace" => parsed.if_running = IfRunning::Replace,
+            "--exit-if-running" => parsed.if_running = IfRunning::Exit,
+            _ if arg.starts_with('-') => usage_error(daemon, usage),
+            _ => parsed.positional.push(arg),
+        }
+    }
+    parsed
+}
+
+fn usage_error(daemon: &str, usage: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--generate-units [DIRECTORY]] [--replace | --exit-if-running] {}",
+        daemon, usage
+    );
+    exit(2)
+}
+
+/// Instance locks of the daemons running in this process, by their short name, held until they
+/// stop
+static LOCKS: Mutex<Vec<(String, InstanceLock)>> = Mutex::new(Vec::new());
+
+/// Make sure that `name` (e.g. `battery`) doesn't run twice, according to `if_running`
+fn lock_instance(daemon: &str, name: &str, if_running: IfRunning) {
+    let result = match if_running {
+        IfRunning::Replace => instance::replace(name, daemon, Duration::from_secs(10)),
+        IfRunning::Fail | IfRunning::Exit => instance::lock(name, daemon),
+    };
+    match result {
+        Ok(lock) => {
+            if let Ok(mut locks) = LOCKS.lock() {
+                locks.push((name.to_string(), lock));
+            }
+        }
+        Err(err @ InstanceError::AlreadyRunning(_)) if if_running == IfRunning::Exit => {
+            info!(target: daemon, "{}: {}, exiting", name, err);
+            exit(0)
+        }
+        Err(err @ InstanceError::AlreadyRunning(_)) => {
+            error!(target: daemon, "{}: {}; use --replace to take over", name, err);
+            exit(1)
+        }
+        Err(err @ InstanceError::Multiplexed(..)) => {
+            error!(target: daemon, "{}: {}; stop it there first", name, err);
+            exit(1)
+        }
+        // Not being able to check is no reason not to run at all
+        Err(err) => warn!(target: daemon, "{}", err),
+    }
+}
+
+/// Daemons running in this process, by their short name (e.g. `pulseaudio`)
+static RUNNING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub(crate) fn short_name(daemon: &str) -> &str {
+    daemon.trim_start_matches(crate::APPNAME).trim_start_matches('-')
+}
+
+/// Whether the daemon `name` (e.g. `pulseaudio`) runs in this process, so others can avoid
+/// showing the same thing twice
+pub fn is_running(name: &str) -> bool {
+    RUNNING
+        .lock()
+        .map(|running| running.iter().any(|n| n == name))
+        .unwrap_or(false)
+}
+
+fn start(daemon: &str, names: &[&str], if_running: IfRunning) {
+    crate::logging::init(daemon);
+    for name in names {
+        lock_instance(daemon, short_name(name), if_running);
+    }
     info!(target: daemon, "Starting");
-    match f() {
+    if let Ok(mut running) = RUNNING.lock() {
+        running.extend(names.iter().map(|name| short_name(name).to_string()));
+    }
+}
+
+/// Forget about a daemon of a multiplexer that has stopped, so that it can be started on its own
+fn stopped(name: &str) {
+    // Failing to start doesn't keep the others from being ready
+    let waited = STARTING
+        .lock()
+        .map(|mut starting| {
+            let waiting = starting.iter().any(|n| n == name);
+            waiting && done_starting(&mut starting, Some(name))
+        })
+        .unwrap_or(false);
+    if waited {
+        ready();
+    }
+    if let Ok(mut running) = RUNNING.lock() {
+        running.retain(|n| n != name);
+    }
+    if let Ok(mut locks) = LOCKS.lock() {
+        locks.retain(|(n, _)| n != name);
+    }
+}
+
+fn finish<E: Display>(daemon: &str, result: Result<(), E>) {
+    match result {
         Ok(_) => {
+            #[cfg(feature = "systemd")]
+            crate::systemd::stopping();
             info!(target: daemon, "Exiting normally")
         }
         Err(err) => {
+            status(&err.to_string());
             error!(target: daemon, "{}", err);
             exit(1)
         }
     };
 }
+
+pub fn run<F, E>(daemon: &str, f: F)
+where
+    F: FnOnce() -> Result<(), E>,
+    E: Display,
+{
+    let args = parse_args(daemon, "");
+    if !args.positional.is_empty() {
+        usage_error(daemon, "")
+    }
+
+    start(daemon, &[daemon], args.if_running);
+    finish(daemon, f());
+}
+
+/// A daemon that can share a process with others, see [`run_many`]
+pub type DaemonFn = Box<dyn FnOnce() -> Result<(), String> + Send>;
+
+/// Run several daemons in one process, each in its own thread.
+///
+/// Daemons are selected by name on the command line, or by the `[daemons]` section of the
+/// `app` config file if there are no arguments. A daemon that fails is logged and the others keep
+/// running, unless it was selected on the command line; the process exits once all have stopped.
+pub fn run_many(app: &'static str, daemons: Vec<(&'static str, DaemonFn)>) {
+    let args = parse_args(app, "[DAEMON...]");
+    let selected = args.positional;
+    // Asking for a daemon by name means it is expected to run, so its failure is the process's
+    let explicit = !selected.is_empty();
+
+    if let Some(unknown) = selected
+        .iter()
+        .find(|name| !daemons.iter().any(|(n, _)| n == name))
+    {
+        eprintln!("Unknown daemon {}", unknown);
+        exit(2)
+    }
+
+    let mut config = Config::new(app);
+    let daemons: Vec<_> = daemons
+        .into_iter()
+        .filter(|(name, _)| {
+            if selected.is_empty() {
+                config.get_default("daemons", name, true)
+            } else {
+                selected.iter().any(|n| n == name)
+            }
+        })
+        .collect();
+
+    let names: Vec<&str> = daemons.iter().map(|(name, _)| *name).collect();
+    start(app, &names, args.if_running);
+    info!(target: app, "Running {}", names.join(", "));
+
+    if let Ok(mut starting) = STARTING.lock() {
+        starting.extend(names.iter().map(|name| name.to_string()));
+    }
+
+    let (sender, receiver) = channel();
+    for (name, f) in daemons {
+        let sender = sender.clone();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(f))
+                    .unwrap_or_else(|_| Err(String::from("panicked")));
+                let _ = sender.send((name, result));
+            })
+            .unwrap_or_else(|err| {
+                error!(target: app, "Failed to start {}: {}", name, err);
+                exit(1)
+            });
+    }
+    drop(sender);
+
+    let mut failed = Vec::new();
+    for (name, result) in receiver {
+        stopped(name);
+        match result {
+            Ok(_) => info!(target: app, "{} exited normally", name),
+            Err(err) if explicit => finish(app, Err(format!("{}: {}", name, err))),
+            Err(err) => {
+                error!(target: app, "{}: {}; the other daemons keep running", name, err);
+                failed.push(name);
+                status(&format!("{} failed", failed.join(", ")));
+            }
+        }
+    }
+    if failed.is_empty() {
+        finish::<String>(app, Ok(()));
+    } else {
+        let failed = failed.join(", ");
+        finish(
+            app,
+            Err(format!("All daemons have stopped, {} failed", failed)),
+        );
+    }
+}
+
+/// A daemon driven by [`run_daemon`] (or [`daemon_fn`] inside a multiplexer), which takes care of
+/// the configuration, the control object, the event loop and the service manager.
+///
+/// See `examples/simple.rs` for a complete daemon.
+pub trait Daemon: Sized {
+    /// Short name, e.g. `vpn`; it names the configuration file, the instance lock and the control
+    /// object (`org.simpleosd.Vpn`)
+    const NAME: &'static str;
+
+    /// Events the daemon schedules with [`Context::add_timer`] or sends from other threads with
+    /// [`Context::sender`]
+    type Event: Clone + Send + 'static;
+
+    type Error: Display + Debug;
+
+    /// Set up the daemon; it is reported as ready once this returns
+    fn init(ctx: &mut Context<Self::Event>) -> Result<Self, Self::Error>;
+
+    fn on_event(
+        &mut self,
+        ctx: &mut Context<Self::Event>,
+        event: Self::Event,
+    ) -> Result<(), Self::Error>;
+
+    /// Handle a request from the control object; by default, `Reload` re-reads the configuration
+    /// file and `ShowCurrent` does nothing
+    fn on_command(
+        &mut self,
+        ctx: &mut Context<Self::Event>,
+        command: Command,
+    ) -> Result<(), Self::Error> {
+        match command {
+            Command::Reload => ctx.reload_config(),
+            Command::ShowCurrent => debug!("{} doesn't implement ShowCurrent", Self::NAME),
+        }
+        Ok(())
+    }
+
+    /// Clean up after [`Context::quit`], or on SIGTERM and SIGINT when running on its own
+    fn shutdown(&mut self, _ctx: &mut Context<Self::Event>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+enum Wrapped<E> {
+    Event(E),
+    Control(Command),
+    Terminate,
+}
+
+/// Sends events to a [`Daemon`] from any thread
+pub struct DaemonSender<E>(EventSender<Wrapped<E>>);
+
+impl<E> Clone for DaemonSender<E> {
+    fn clone(&self) -> Self {
+        DaemonSender(self.0.clone())
+    }
+}
+
+impl<E> DaemonSender<E> {
+    /// Returns false if the daemon is gone
+    pub fn send(&self, event: E) -> bool {
+        self.0.send(Wrapped::Event(event))
+    }
+}
+
+/// Everything a [`Daemon`] gets from its surroundings
+pub struct Context<E> {
+    name: &'static str,
+    config: Config,
+    control: Control,
+    events: EventLoop<'static, Wrapped<E>>,
+    /// What the descriptors registered with `add_fd` belong to, dropped after `events`
+    sources: Vec<Rc<dyn AsFd>>,
+    quit: bool,
+}
+
+impl<E: Clone + Send + 'static> Context<E> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The daemon's own configuration file
+    pub fn config(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    pub fn reload_config(&mut self) {
+        info!("Reloading configuration");
+        self.config = Config::new(self.name);
+    }
+
+    /// A builder for OSDs tied to the daemon's control object, so that they respect `Suppress()`
+    pub fn osd(&self) -> OSDBuilder {
+        OSD::builder().control(self.control.handle())
+    }
+
+    /// Produce `event` every `interval`
+    pub fn add_timer(&mut self, interval: Duration, event: E) -> TimerId {
+        self.events.add_timer(interval, Wrapped::Event(event))
+    }
+
+    /// Produce `event` once, `after` from now
+    pub fn add_oneshot(&mut self, after: Duration, event: E) -> TimerId {
+        self.events.add_oneshot(after, Wrapped::Event(event))
+    }
+
+    /// See [`EventLoop::set_timer`]
+    pub fn set_timer(&mut self, id: TimerId, after: Option<Duration>) {
+        self.events.set_timer(id, after)
+    }
+
+    /// Produce `event` whenever `source` (an inotify instance, a socket) is readable; it is kept
+    /// open for as long as the daemon runs
+    pub fn add_fd<F: AsFd + 'static>(&mut self, source: Rc<F>, event: E) -> io::Result<()> {
+        // `source` is kept alive in `sources` until the event loop is gone
+        let fd = unsafe { BorrowedFd::borrow_raw(source.as_fd().as_raw_fd()) };
+        self.events.add_fd(fd, Wrapped::Event(event))?;
+        self.sources.push(source);
+        Ok(())
+    }
+
+    pub fn sender(&self) -> DaemonSender<E> {
+        DaemonSender(self.events.sender())
+    }
+
+    /// Stop after the current event, calling [`Daemon::shutdown`]
+    pub fn quit(&mut self) {
+        self.quit = true;
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DaemonError<E: Display + Debug> {
+    #[error("{0}")]
+    Daemon(E),
+    #[error("Event loop failed: {0}")]
+    EventLoop(#[from] io::Error),
+}
+
+/// Write end of the pipe SIGTERM and SIGINT are reported through
+static TERMINATION_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_termination(_signal: libc::c_int) {
+    let fd = TERMINATION_WRITE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // write(2) is async-signal-safe; if the pipe is full, the loop is about to wake up anyway
+        unsafe {
+            libc::write(fd, b"t".as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+/// A descriptor that becomes readable once the process is asked to terminate
+fn termination_fd() -> io::Result<BorrowedFd<'static>> {
+    static PIPE: OnceLock<(UnixStream, UnixStream)> = OnceLock::new();
+    if PIPE.get().is_none() {
+        let (read, write) = UnixStream::pair()?;
+        write.set_nonblocking(true)?;
+        let _ = PIPE.set((read, write));
+    }
+    let (read, write) = PIPE.get().expect("the pipe has just been set");
+    TERMINATION_WRITE.store(write.as_raw_fd(), Ordering::Relaxed);
+    let handler = on_termination as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+    Ok(read.as_fd())
+}
+
+fn run_events<D: Daemon>(
+    termination: Option<BorrowedFd<'static>>,
+) -> Result<(), DaemonError<D::Error>> {
+    let mut events = EventLoop::new()?;
+    if let Some(fd) = termination {
+        events.add_fd(fd, Wrapped::Terminate)?;
+    }
+
+    let sender = events.sender();
+    let control = Control::new_or_warn(D::NAME, move |command| {
+        sender.send(Wrapped::Control(command));
+    });
+
+    let mut ctx = Context {
+        name: D::NAME,
+        config: Config::new(D::NAME),
+        control,
+        events,
+        sources: Vec::new(),
+        quit: false,
+    };
+
+    let mut daemon = D::init(&mut ctx).map_err(DaemonError::Daemon)?;
+    ready();
+
+    while !ctx.quit {
+        let result = match ctx.events.wait()? {
+            Wrapped::Event(event) => daemon.on_event(&mut ctx, event),
+            Wrapped::Control(command) => daemon.on_command(&mut ctx, command),
+            Wrapped::Terminate => {
+                info!("Asked to terminate");
+                ctx.quit();
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            let _ = daemon.shutdown(&mut ctx);
+            return Err(DaemonError::Daemon(err));
+        }
+    }
+
+    daemon.shutdown(&mut ctx).map_err(DaemonError::Daemon)
+}
+
+/// Run `D` as the only daemon of this process (`simple-osd-$NAME`); the equivalent of [`run`] for
+/// a [`Daemon`]
+pub fn run_daemon<D: Daemon>() {
+    run(&format!("{}-{}", crate::APPNAME, D::NAME), || {
+        let termination = termination_fd()
+            .map_err(|err| warn!("Failed to handle termination signals: {}", err))
+            .ok();
+        run_events::<D>(termination)
+    })
+}
+
+/// `D` as a daemon for [`run_many`]; it keeps running until the whole process is stopped
+pub fn daemon_fn<D: Daemon>() -> DaemonFn {
+    Box::new(|| run_events::<D>(None).map_err(|err| err.to_string()))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{dbus_service, unit};
+    #[test]
+    fn runs_in_graphical_session() {
+        let u = unit("battery", "/usr/bin/simple-osd-battery");
+        assert!(u.contains("ExecStart=/usr/bin/simple-osd-battery\n"));
+        assert!(u.contains("After=graphical-session.target\n"));
+        assert!(u.contains("WantedBy=graphical-session.target\n"));
+        assert!(u.contains("Restart=on-failure\n"));
+        assert_eq!(u.contains("WatchdogSec=30\n"), cfg!(feature = "systemd"));
+    }
+
+    #[test]
+    fn activates_the_unit() {
+        let service = dbus_service(
+            "org.simpleosd.Battery",
+            "simple-osd-battery",
+            "/usr/bin/simple-osd-battery",
+        );
+        assert!(service.starts_with("[D-BUS Service]\n"));
+        assert!(service.contains("Name=org.simpleosd.Battery\n"));
+        assert!(service.contains("Exec=/usr/bin/simple-osd-battery\n"));
+        assert!(service.contains("SystemdService=simple-osd-battery.service\n"));
+    }
+}
+
+#[cfg(test)]
+mod ready_tests {
+    use super::done_starting;
+
+    #[test]
+    fn waits_for_every_daemon() {
+        let mut starting = vec![String::from("battery"), String::from("mpris")];
+        assert!(!done_starting(&mut starting, Some("battery")));
+        assert!(!done_starting(&mut starting, Some("battery")));
+        assert!(!done_starting(&mut starting, None));
+        assert!(done_starting(&mut starting, Some("mpris")));
+    }
+
+    #[test]
+    fn a_single_daemon_is_ready_at_once() {
+        assert!(done_starting(&mut Vec::new(), Some("main")));
+    }
+}
@@ -0,0 +1,244 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! A tiny single-threaded event loop for the daemons.
+//!
+//! Sources (timers, file descriptors and channels fed from other threads) are registered together
+//! with the event they produce, and [`EventLoop::wait`] sleeps until one of them fires. Anything
+//! that has its own blocking API (D-Bus signal streams, the pulseaudio mainloop) can run in a
+//! thread and report back through an [`EventSender`].
+
+use polling::{Event, Events, PollMode, Poller};
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::daemon::Heartbeat;
+
+/// Handle of a timer registered with [`EventLoop::add_timer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+struct Timer<T> {
+    interval: Option<Duration>,
+    deadline: Option<Instant>,
+    event: T,
+}
+
+/// Sends events into an [`EventLoop`] from any thread, waking it up
+pub struct EventSender<T> {
+    sender: Sender<T>,
+    poller: Arc<Poller>,
+}
+
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        EventSender {
+            sender: self.sender.clone(),
+            poller: self.poller.clone(),
+        }
+    }
+}
+
+impl<T> EventSender<T> {
+    /// Returns false if the event loop is gone
+    pub fn send(&self, event: T) -> bool {
+        if self.sender.send(event).is_err() {
+            return false;
+        }
+        self.poller
+            .notify()
+            .map_err(|err| warn!("Failed to wake up the event loop: {}", err))
+            .is_ok()
+    }
+}
+
+pub struct EventLoop<'a, T> {
+    poller: Arc<Poller>,
+    events: Events,
+    timers: Vec<Timer<T>>,
+    fds: Vec<Option<(BorrowedFd<'a>, T)>>,
+    sender: Sender<T>,
+    receiver: Receiver<T>,
+    pending: VecDeque<T>,
+    /// Keeps the watchdog pinged for as long as the loop is waited on
+    heartbeat: Heartbeat,
+}
+
+impl<'a, T: Clone> EventLoop<'a, T> {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = channel();
+        Ok(EventLoop {
+            poller: Arc::new(Poller::new()?),
+            events: Events::new(),
+            timers: Vec::new(),
+            fds: Vec::new(),
+            sender,
+            receiver,
+            pending: VecDeque::new(),
+            heartbeat: Heartbeat::register(),
+        })
+    }
+
+    /// Produce `event` every `interval`, starting one `interval` from now
+    pub fn add_timer(&mut self, interval: Duration, event: T) -> TimerId {
+        self.timers.push(Timer {
+            interval: Some(interval),
+            deadline: Some(Instant::now() + interval),
+            event,
+        });
+        TimerId(self.timers.len() - 1)
+    }
+
+    /// Produce `event` once, `after` from now
+    pub fn add_oneshot(&mut self, after: Duration, event: T) -> TimerId {
+        let id = self.add_timer(after, event);
+        self.timers[id.0].interval = None;
+        id
+    }
+
+    /// Re-arm a timer to fire `after` from now (and then every `after` if it is periodic), or
+    /// disarm it with `None`
+    pub fn set_timer(&mut self, id: TimerId, after: Option<Duration>) {
+        let timer = &mut self.timers[id.0];
+        match after {
+            Some(after) => {
+                if timer.interval.is_some() {
+                    timer.interval = Some(after);
+                }
+                timer.deadline = Some(Instant::now() + after);
+            }
+            None => timer.deadline = None,
+        }
+    }
+
+    /// Produce `event` whenever `fd` is readable
+    pub fn add_fd(&mut self, fd: BorrowedFd<'a>, event: T) -> io::Result<()> {
+        let key = self.fds.len();
+        // The descriptor is borrowed for 'a, so it stays open for as long as it is registered
+        unsafe {
+            self.poller
+                .add_with_mode(fd.as_raw_fd(), Event::readable(key), PollMode::Level)?;
+        }
+        self.fds.push(Some((fd, event)));
+        Ok(())
+    }
+
+    /// Stop watching `fd`
+    pub fn remove_fd(&mut self, fd: impl AsFd) -> io::Result<()> {
+        let raw = fd.as_fd().as_raw_fd();
+        if let Some(slot) = self
+            .fds
+            .iter_mut()
+            .find(|slot| matches!(slot, Some((f, _)) if f.as_raw_fd() == raw))
+        {
+            if let Some((fd, _)) = slot.take() {
+                self.poller.delete(fd)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A handle other threads can use to feed events into this loop
+    pub fn sender(&self) -> EventSender<T> {
+        EventSender {
+            sender: self.sender.clone(),
+            poller: self.poller.clone(),
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().filter_map(|t| t.deadline).min()
+    }
+
+    fn collect(&mut self) {
+        while let Ok(event) = self.receiver.try_recv() {
+            self.pending.push_back(event);
+        }
+
+        for ev in self.events.iter() {
+            if let Some(Some((_, event))) = self.fds.get(ev.key) {
+                self.pending.push_back(event.clone());
+            }
+        }
+        self.events.clear();
+
+        let now = Instant::now();
+        for timer in self.timers.iter_mut() {
+            if matches!(timer.deadline, Some(deadline) if deadline <= now) {
+                self.pending.push_back(timer.event.clone());
+                timer.deadline = timer.interval.map(|interval| now + interval);
+            }
+        }
+    }
+
+    /// Sleep until the next event and return it
+    pub fn wait(&mut self) -> io::Result<T> {
+        loop {
+            self.heartbeat.beat();
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            let timeout = self
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            // Wake up in time for the next beat even if nothing happens
+            let timeout = match (timeout, self.heartbeat.period()) {
+                (Some(timeout), Some(period)) => Some(timeout.min(period)),
+                (timeout, period) => timeout.or(period),
+            };
+
+            trace!("Waiting for events, timeout {:?}", timeout);
+            self.poller.wait(&mut self.events, timeout)?;
+            self.collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn timers_fire_in_order() {
+        let mut ev = EventLoop::new().unwrap();
+        ev.add_timer(Duration::from_millis(30), "slow");
+        ev.add_oneshot(Duration::from_millis(10), "fast");
+        assert_eq!(ev.wait().unwrap(), "fast");
+        assert_eq!(ev.wait().unwrap(), "slow");
+        assert_eq!(ev.wait().unwrap(), "slow");
+    }
+
+    #[test]
+    fn disarmed_timer_does_not_fire() {
+        let mut ev = EventLoop::new().unwrap();
+        let t = ev.add_timer(Duration::from_millis(5), "disarmed");
+        ev.add_oneshot(Duration::from_millis(20), "armed");
+        ev.set_timer(t, None);
+        assert_eq!(ev.wait().unwrap(), "armed");
+    }
+
+    #[test]
+    fn sender_wakes_up_the_loop() {
+        let mut ev = EventLoop::new().unwrap();
+        let sender = ev.sender();
+        std::thread::spawn(move || sender.send(42));
+        assert_eq!(ev.wait().unwrap(), 42);
+    }
+
+    #[test]
+    fn readable_fd() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let mut ev = EventLoop::new().unwrap();
+        ev.add_fd(b.as_fd(), "readable").unwrap();
+        ev.add_oneshot(Duration::from_secs(5), "timeout");
+        a.write_all(b"x").unwrap();
+        assert_eq!(ev.wait().unwrap(), "readable");
+    }
+}
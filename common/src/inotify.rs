@@ -0,0 +1,129 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Tells when files are modified, which for sysfs attributes means written to by userspace or
+//! changed by a driver that notifies about it (`sysfs_notify`). Not every driver does, so daemons
+//! should keep polling as an option.
+//!
+//! [`Inotify`] can be registered with [`EventLoop::add_fd`](crate::event::EventLoop::add_fd),
+//! and [`Inotify::receive`] then called whenever it fires.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+/// A watch added with [`Inotify::watch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watch(libc::c_int);
+
+/// A non-blocking inotify instance
+pub struct Inotify {
+    fd: OwnedFd,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Watch `path` for modifications
+    pub fn watch(&self, path: &Path) -> io::Result<Watch> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let wd =
+            unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), libc::IN_MODIFY) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watch(wd))
+    }
+
+    /// Stop watching; the watch is gone anyway if its file has been removed
+    pub fn unwatch(&self, watch: Watch) {
+        unsafe {
+            libc::inotify_rm_watch(self.fd.as_raw_fd(), watch.0);
+        }
+    }
+
+    /// The watches that have fired since the last call, without blocking
+    pub fn receive(&self) -> io::Result<Vec<Watch>> {
+        let mut watches = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let received = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if received < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(watches),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(err),
+                };
+            }
+
+            // Events are laid out one after the other, each followed by a name of `len` bytes
+            let mut offset = 0;
+            let header = mem::size_of::<libc::inotify_event>();
+            while offset + header <= received as usize {
+                let event = unsafe {
+                    (buffer.as_ptr().add(offset) as *const libc::inotify_event).read_unaligned()
+                };
+                if event.mask & libc::IN_IGNORED == 0 && !watches.contains(&Watch(event.wd)) {
+                    watches.push(Watch(event.wd));
+                }
+                offset += header + event.len as usize;
+            }
+        }
+    }
+}
+
+impl AsFd for Inotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod inotify_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn tells_which_files_are_modified() {
+        let root = std::env::temp_dir().join(format!("simple-osd-inotify-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let (brightness, actual) = (root.join("brightness"), root.join("actual_brightness"));
+        fs::write(&brightness, "10\n").unwrap();
+        fs::write(&actual, "10\n").unwrap();
+
+        let inotify = Inotify::new().unwrap();
+        let brightness_watch = inotify.watch(&brightness).unwrap();
+        let actual_watch = inotify.watch(&actual).unwrap();
+        assert_eq!(inotify.receive().unwrap(), vec![]);
+
+        fs::write(&brightness, "20\n").unwrap();
+        fs::write(&brightness, "30\n").unwrap();
+        assert_eq!(inotify.receive().unwrap(), vec![brightness_watch]);
+
+        inotify.unwatch(brightness_watch);
+        fs::write(&brightness, "40\n").unwrap();
+        fs::write(&actual, "40\n").unwrap();
+        assert_eq!(inotify.receive().unwrap(), vec![actual_watch]);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
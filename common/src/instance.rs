@@ -0,0 +1,140 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! One instance per daemon, enforced with `flock`ed files under `$XDG_RUNTIME_DIR/simple-osd/`.
+//! The files hold the pid and the name of the process holding the lock.
+//!
+//! The lock is tied to the open file, so it goes away with the process no matter how it exits,
+//! and a stale lock file left behind after a crash doesn't get in the way.
+
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use xdg::BaseDirectories;
+
+#[derive(Error, Debug)]
+pub enum InstanceError {
+    #[error("Already running (pid {})", .0.map_or(String::from("unknown"), |pid| pid.to_string()))]
+    AlreadyRunning(Option<u32>),
+    #[error("Running inside {1} (pid {0}), which would take its other daemons down when replaced")]
+    Multiplexed(u32, String),
+    #[error("Failed to set up XDG Base Directories: {0}")]
+    BaseDirectories(#[from] xdg::BaseDirectoriesError),
+    #[error("Failed to lock {0}: {1}")]
+    Io(String, io::Error),
+}
+
+/// Held for as long as the daemon runs; dropping it lets another instance start
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+fn lock_file(path: &Path, owner: &str) -> Result<InstanceLock, InstanceError> {
+    let io_error = |err| InstanceError::Io(path.display().to_string(), err);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(io_error)?;
+
+    match file.try_lock() {
+        Ok(()) => {
+            file.set_len(0).map_err(io_error)?;
+            write!(file, "{}\n{}\n", process::id(), owner).map_err(io_error)?;
+            Ok(InstanceLock { _file: file })
+        }
+        Err(std::fs::TryLockError::WouldBlock) => {
+            let mut contents = String::new();
+            file.seek(SeekFrom::Start(0))
+                .and_then(|_| file.read_to_string(&mut contents))
+                .map_err(io_error)?;
+            let pid = contents
+                .lines()
+                .next()
+                .and_then(|pid| pid.trim().parse().ok());
+            Err(InstanceError::AlreadyRunning(pid))
+        }
+        Err(std::fs::TryLockError::Error(err)) => Err(io_error(err)),
+    }
+}
+
+/// The name of the process holding the lock at `path`, if it has recorded one
+fn owner(path: &Path) -> Option<String> {
+    read_to_string(path).ok()?.lines().nth(1).map(String::from)
+}
+
+fn lock_path(daemon: &str) -> Result<PathBuf, InstanceError> {
+    BaseDirectories::with_prefix(crate::APPNAME)?
+        .place_runtime_file(format!("{}.lock", daemon))
+        .map_err(|err| InstanceError::Io(daemon.to_string(), err))
+}
+
+/// Take the instance lock of `daemon` (e.g. `battery`) for the process `owner` (e.g.
+/// `simple-osd-battery`, or `simple-osd` when it runs several daemons)
+pub fn lock(daemon: &str, owner: &str) -> Result<InstanceLock, InstanceError> {
+    lock_file(&lock_path(daemon)?, owner)
+}
+
+/// Take the instance lock of `daemon`, asking the running instance to exit first if there is one.
+///
+/// A lock held by a process running other daemons too is only replaced by another one like it.
+pub fn replace(
+    daemon: &str,
+    owner: &str,
+    timeout: Duration,
+) -> Result<InstanceLock, InstanceError> {
+    let path = lock_path(daemon)?;
+    let pid = match lock_file(&path, owner) {
+        Err(InstanceError::AlreadyRunning(Some(pid))) => pid,
+        result => return result,
+    };
+
+    if let Some(holder) = self::owner(&path) {
+        if holder != owner && crate::daemon::short_name(&holder) != daemon {
+            return Err(InstanceError::Multiplexed(pid, holder));
+        }
+    }
+
+    info!("Replacing the running instance (pid {})", pid);
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        warn!("Failed to stop pid {}: {}", pid, io::Error::last_os_error());
+    }
+
+    let started = Instant::now();
+    loop {
+        match lock_file(&path, owner) {
+            Err(InstanceError::AlreadyRunning(_)) if started.elapsed() < timeout => {
+                sleep(Duration::from_millis(100))
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_fails_until_first_is_dropped() {
+        let path = std::env::temp_dir().join(format!("simple-osd-test-{}.lock", process::id()));
+        let first = lock_file(&path, "simple-osd-test").unwrap();
+        match lock_file(&path, "simple-osd-test") {
+            Err(InstanceError::AlreadyRunning(pid)) => assert_eq!(pid, Some(process::id())),
+            other => panic!("Expected AlreadyRunning, got {:?}", other),
+        }
+        assert_eq!(owner(&path).as_deref(), Some("simple-osd-test"));
+        drop(first);
+        assert!(lock_file(&path, "simple-osd").is_ok());
+        assert_eq!(owner(&path).as_deref(), Some("simple-osd"));
+        let _ = std::fs::remove_file(path);
+    }
+}
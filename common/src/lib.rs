@@ -1,6 +1,47 @@
 // This is free and unencumbered software released into the public domain.
 // balsoft 2020
 
+//! Building blocks of the simple-osd daemons, for writing daemons of your own.
+//!
+//! A daemon implements [`daemon::Daemon`] and is started with [`daemon::run_daemon`]:
+//!
+//! ```no_run
+//! use simple_osd_common::daemon::{run_daemon, Context, Daemon};
+//! use simple_osd_common::notify::{OSDContents, OSD};
+//! use std::time::Duration;
+//!
+//! struct Vpn {
+//!     osd: OSD,
+//! }
+//!
+//! impl Daemon for Vpn {
+//!     const NAME: &'static str = "vpn";
+//!     type Event = ();
+//!     type Error = simple_osd_common::notify::UpdateError;
+//!
+//!     fn init(ctx: &mut Context<()>) -> Result<Vpn, Self::Error> {
+//!         let interval = ctx.config().get_default("default", "refresh interval", 5);
+//!         ctx.add_timer(Duration::from_secs(interval), ());
+//!         Ok(Vpn {
+//!             osd: ctx.osd().title("VPN").icon("network-vpn").build(),
+//!         })
+//!     }
+//!
+//!     fn on_event(&mut self, _ctx: &mut Context<()>, _event: ()) -> Result<(), Self::Error> {
+//!         self.osd.contents = OSDContents::Simple(Some(String::from("Connected")));
+//!         self.osd.update()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     run_daemon::<Vpn>()
+//! }
+//! ```
+//!
+//! It then gets the same command line options, configuration files, OSD styling, logging,
+//! instance locking, D-Bus control object and systemd integration as the daemons shipped here.
+//! [`notify::OSD::builder`] and [`config::Config`] can also be used on their own.
+
 extern crate configparser;
 extern crate notify_rust;
 extern crate xdg;
@@ -13,6 +54,23 @@ pub static APPNAME: &str = "simple-osd";
 
 pub mod daemon;
 
+#[cfg(feature = "systemd")]
+pub mod systemd;
+
 pub mod config;
 
+pub mod control;
+
+pub mod event;
+
+pub mod inotify;
+
+pub mod instance;
+
+pub mod logging;
+
 pub mod notify;
+
+pub mod supervisor;
+
+pub mod uevent;
@@ -0,0 +1,452 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Logging set up from the `[logging]` section of the `common` configuration file.
+//!
+//! Records go to stderr (with journald priorities when stderr is the journal), to a rotating file
+//! under `$XDG_STATE_HOME/simple-osd/`, or straight to journald or syslog. Key-value pairs
+//! attached to records (`info!(sink = name; "...")`) are kept as fields in journald and appended
+//! as `key=value` everywhere else.
+
+use std::env;
+use std::fmt;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use xdg::BaseDirectories;
+
+use crate::config::Config;
+use crate::daemon::DAEMONS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Stderr,
+    File,
+    Journald,
+    Syslog,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(Output::Stderr),
+            "file" => Ok(Output::File),
+            "journald" => Ok(Output::Journald),
+            "syslog" => Ok(Output::Syslog),
+            _ => Err(format!(
+                "Unknown log output {}, must be stderr, file, journald or syslog",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Output::Stderr => "stderr",
+            Output::File => "file",
+            Output::Journald => "journald",
+            Output::Syslog => "syslog",
+        })
+    }
+}
+
+struct Settings {
+    /// Filter in `RUST_LOG` syntax; `RUST_LOG` itself takes precedence
+    filter: String,
+    output: Output,
+    max_file_size: u64,
+    max_files: u32,
+}
+
+impl Settings {
+    fn load(config: &mut Config) -> Settings {
+        let level = config.get_default("logging", "level", String::from("error"));
+        let filter = config.get_default("logging", "filter", String::new());
+        Settings {
+            filter: env::var("RUST_LOG").unwrap_or_else(|_| {
+                if filter.is_empty() {
+                    level
+                } else {
+                    format!("{},{}", level, filter)
+                }
+            }),
+            output: config.get_default("logging", "output", Output::Stderr),
+            max_file_size: config.get_default("logging", "max file size", 1024) * 1024,
+            max_files: config.get_default("logging", "max files", 5),
+        }
+    }
+}
+
+/// Let filters name daemons the short way: `battery=debug` -> `simple_osd_battery=debug`
+fn expand_filter(filter: &str) -> String {
+    filter
+        .split(',')
+        .map(|directive| {
+            let (module, rest) = directive
+                .find(['=', ':'])
+                .map_or((directive, ""), |i| directive.split_at(i));
+            if DAEMONS.contains(&module) {
+                format!("{}_{}{}", crate::APPNAME.replace('-', "_"), module, rest)
+            } else {
+                directive.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod expand_filter_tests {
+    use super::expand_filter;
+    #[test]
+    fn expands_daemon_names() {
+        assert_eq!(
+            expand_filter("warn,battery=debug,simple_osd_common::notify=trace"),
+            "warn,simple_osd_battery=debug,simple_osd_common::notify=trace"
+        );
+    }
+    #[test]
+    fn keeps_bare_daemon_names() {
+        assert_eq!(expand_filter("pulseaudio"), "simple_osd_pulseaudio");
+    }
+}
+
+/// Collects the key-value pairs of a record
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn fields(record: &Record) -> Vec<(String, String)> {
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+    fields.0
+}
+
+/// `message key=value key2="value 2"`
+fn format_message(record: &Record) -> String {
+    let mut message = record.args().to_string();
+    for (key, value) in fields(record) {
+        if value.is_empty() || value.contains(char::is_whitespace) {
+            message.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            message.push_str(&format!(" {}={}", key, value));
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod format_message_tests {
+    use super::format_message;
+    use log::{Level, Record};
+    #[test]
+    fn appends_fields() {
+        let kvs = [("sink", "alsa_output.pci"), ("device", "Headphones 2")];
+        let record = Record::builder()
+            .args(format_args!("Volume changed"))
+            .level(Level::Info)
+            .key_values(&kvs)
+            .build();
+        assert_eq!(
+            format_message(&record),
+            "Volume changed sink=alsa_output.pci device=\"Headphones 2\""
+        );
+    }
+}
+
+/// sd-daemon(3) and syslog priorities
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// A log file that is moved to `<name>.1` (and `.1` to `.2` and so on) when it grows too big
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: u32) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                rename(from, self.rotated(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rotating_file_tests {
+    use super::RotatingFile;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all};
+    #[test]
+    fn rotates_and_keeps_max_files() {
+        let dir = std::env::temp_dir().join(format!("simple-osd-log-test-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let mut file = RotatingFile::open(dir.join("test.log"), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"].iter() {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(read_to_string(dir.join("test.log")).unwrap(), "fourth\n");
+        assert_eq!(read_to_string(dir.join("test.log.1")).unwrap(), "third\n");
+        assert_eq!(read_to_string(dir.join("test.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("test.log.3").exists());
+        remove_dir_all(dir).unwrap();
+    }
+}
+
+enum Sink {
+    File(Mutex<RotatingFile>),
+    Journald(UnixDatagram),
+    Syslog(UnixDatagram),
+}
+
+/// Logger for the outputs env_logger doesn't know about
+struct Logger {
+    identifier: String,
+    filter: Filter,
+    sink: Sink,
+}
+
+/// A field in the journald native protocol; values with newlines need the binary form
+fn journal_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    } else {
+        buf.push(b'=');
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf.push(b'\n');
+}
+
+/// journald field names are upper case letters, digits and underscores
+fn journal_key(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        .trim_start_matches('_')
+        .to_string()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let result = match &self.sink {
+            Sink::File(file) => {
+                let line = format!(
+                    "{} {:<5} {} > {}\n",
+                    humantime::format_rfc3339_seconds(SystemTime::now()),
+                    record.level(),
+                    record.target(),
+                    format_message(record)
+                );
+                file.lock()
+                    .map_err(|_| io::Error::other("log file lock poisoned"))
+                    .and_then(|mut file| file.write_line(&line))
+            }
+            Sink::Journald(socket) => {
+                let mut buf = Vec::new();
+                journal_field(&mut buf, "MESSAGE", &record.args().to_string());
+                journal_field(&mut buf, "PRIORITY", &priority(record.level()).to_string());
+                journal_field(&mut buf, "SYSLOG_IDENTIFIER", &self.identifier);
+                journal_field(&mut buf, "CODE_MODULE", record.target());
+                for (key, value) in fields(record) {
+                    journal_field(&mut buf, &journal_key(&key), &value);
+                }
+                socket.send(&buf).map(|_| ())
+            }
+            Sink::Syslog(socket) => {
+                // Facility "user"
+                let message = format!(
+                    "<{}>{}[{}]: {}: {}",
+                    8 + priority(record.level()),
+                    self.identifier,
+                    process::id(),
+                    record.target(),
+                    format_message(record)
+                );
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to log: {}", err);
+        }
+    }
+
+    fn flush(&self) {
+        if let Sink::File(file) = &self.sink {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+fn open_sink(daemon: &str, settings: &Settings) -> Result<Sink, String> {
+    match settings.output {
+        Output::File => {
+            let path = BaseDirectories::with_prefix(crate::APPNAME)
+                .map_err(|err| format!("Failed to set up XDG Base Directories: {}", err))?
+                .place_state_file(format!("{}.log", daemon))
+                .map_err(|err| format!("Failed to create the log directory: {}", err))?;
+            RotatingFile::open(path.clone(), settings.max_file_size, settings.max_files)
+                .map(|file| Sink::File(Mutex::new(file)))
+                .map_err(|err| format!("Failed to open {:?}: {}", path, err))
+        }
+        Output::Journald => connect("/run/systemd/journal/socket")
+            .map(Sink::Journald)
+            .map_err(|err| format!("Failed to connect to journald: {}", err)),
+        Output::Syslog => connect("/dev/log")
+            .map(Sink::Syslog)
+            .map_err(|err| format!("Failed to connect to syslog: {}", err)),
+        Output::Stderr => Err(String::from("stderr is not a sink")),
+    }
+}
+
+fn init_stderr(filter: &str) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.parse_filters(filter);
+
+    #[cfg(feature = "systemd")]
+    if crate::systemd::journal_stream() {
+        // journald understands sd-daemon(3) priority prefixes and adds its own timestamps
+        builder.format(|f, record| {
+            writeln!(
+                f,
+                "<{}>{}: {}",
+                priority(record.level()),
+                record.target(),
+                format_message(record)
+            )
+        });
+        builder.init();
+        return;
+    }
+
+    builder.format(|f, record| {
+        writeln!(
+            f,
+            " {:<5} {} > {}",
+            f.default_styled_level(record.level()),
+            record.target(),
+            format_message(record)
+        )
+    });
+    builder.init();
+}
+
+/// Set up logging for `daemon` (e.g. `simple-osd-battery`), falling back to stderr if the
+/// configured output is unavailable
+pub fn init(daemon: &str) {
+    let settings = Settings::load(&mut Config::new("common"));
+    let filter = expand_filter(&settings.filter);
+
+    if settings.output == Output::Stderr {
+        init_stderr(&filter);
+        return;
+    }
+
+    match open_sink(daemon, &settings) {
+        Ok(sink) => {
+            let logger = Logger {
+                identifier: daemon.to_string(),
+                filter: FilterBuilder::new().parse(&filter).build(),
+                sink,
+            };
+            log::set_max_level(logger.filter.filter());
+            if log::set_boxed_logger(Box::new(logger)).is_err() {
+                eprintln!("Logging has already been set up");
+            }
+        }
+        Err(err) => {
+            init_stderr(&filter);
+            warn!("{}, logging to stderr", err);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 use crate::config::Config;
+use crate::control;
 pub use notify_rust::Urgency;
-use notify_rust::{CloseHandler, CloseReason, Hint, Notification, NotificationHandle};
+use notify_rust::{
+    handle_action, ActionResponse, CloseHandler, CloseReason, Hint, Notification, NotificationHandle,
+    Timeout,
+};
 use std::default::Default;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -22,6 +26,125 @@ impl Default for OSDContents {
     }
 }
 
+/// How OSDs look, from the `[notification]` and `[progressbar]` sections of the `common` config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Style {
+    /// Expiration timeout in milliseconds; -1 means the default of the notification server
+    pub timeout: i32,
+
+    /// Pass progress as the freedesktop `value` hint instead of drawing it in the body
+    pub hint: bool,
+
+    /// Length of the progress bar in characters
+    pub length: i32,
+
+    pub full: String,
+    pub empty: String,
+
+    pub start: String,
+    pub end: String,
+}
+
+impl Style {
+    pub fn load(config: &mut Config) -> Style {
+        let default = Style::default();
+        Style {
+            timeout: config.get("notification", "default timeout").unwrap_or(default.timeout),
+            // Progress doesn't go down for the same notification, at least in mako, so disable it by default
+            hint: config.get_default("progressbar", "use freedesktop notification hint", default.hint),
+            length: config.get_default("progressbar", "length", default.length),
+            full: config.get_default("progressbar", "full", default.full),
+            empty: config.get_default("progressbar", "empty", default.empty),
+            start: config.get_default("progressbar", "start", default.start),
+            end: config.get_default("progressbar", "end", default.end),
+        }
+    }
+
+    /// The body of a progress OSD
+    pub fn render(&self, value: f32, text: &OSDProgressText) -> String {
+        let mut s = String::new();
+
+        if !self.hint {
+            trace!("Hint is false, generating progressbar");
+
+            s.push_str(self.start.as_str());
+
+            for _ in 0..(value * self.length as f32) as i32 {
+                s.push_str(self.full.as_str())
+            }
+
+            for _ in (value * self.length as f32) as i32..self.length {
+                s.push_str(self.empty.as_str())
+            }
+
+            s.push_str(self.end.as_str());
+
+            s.push(' ');
+        }
+
+        match text {
+            OSDProgressText::Percentage => {
+                s.push_str(((value * 100.) as i32).to_string().as_str());
+
+                s.push('%');
+            }
+            OSDProgressText::Text(text) => {
+                if let Some(text) = text.as_ref() {
+                    s.push_str(text.as_str())
+                };
+            }
+        }
+
+        s
+    }
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            timeout: -1,
+            hint: false,
+            length: 20,
+            full: String::from("█"),
+            empty: String::from("░"),
+            start: String::new(),
+            end: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod style_tests {
+    use super::{OSDProgressText, Style};
+    #[test]
+    fn renders_progress_bar() {
+        let style = Style {
+            length: 4,
+            full: String::from("#"),
+            empty: String::from("-"),
+            start: String::from("["),
+            end: String::from("]"),
+            ..Style::default()
+        };
+        assert_eq!(style.render(0.5, &OSDProgressText::Percentage), "[##--] 50%");
+        assert_eq!(
+            style.render(0.25, &OSDProgressText::Text(Some(String::from("1/4")))),
+            "[#---] 1/4"
+        );
+    }
+    #[test]
+    fn leaves_progress_to_the_hint() {
+        let style = Style {
+            hint: true,
+            ..Style::default()
+        };
+        assert_eq!(style.render(0.5, &OSDProgressText::Percentage), "50%");
+    }
+}
+
+/// Called with the identifier of a clicked button
+pub type ActionHandler = Box<dyn FnMut(&str) + Send>;
+
 pub struct OSD {
     pub title: Option<String>,
 
@@ -31,23 +154,22 @@ pub struct OSD {
 
     pub urgency: Urgency,
 
+    /// Expiration timeout in milliseconds; -1 means the default of the notification server
     pub timeout: i32,
 
-    // Progress bar stuff
-    hint: bool,
+    /// Control object of the daemon this OSD belongs to, for suppression and `LastState`
+    pub control: Option<control::Handle>,
 
-    length: i32,
+    pub style: Style,
 
-    full: String,
-    empty: String,
-
-    start: String,
-    end: String,
+    /// Buttons as `(identifier, label)`; see [`OSD::on_action`]
+    pub actions: Vec<(String, String)>,
 
     // Internal notification
     notification: Notification,
     id: Arc<Mutex<Option<u32>>>,
     on_close_handler: Arc<Mutex<Box<dyn CloseHandler<CloseReason> + Send + Sync>>>,
+    on_action_handler: Arc<Mutex<ActionHandler>>,
 }
 
 #[derive(Error, Debug)]
@@ -74,85 +196,121 @@ pub enum UpdateError {
     NotificationShowError(#[from] notify_rust::error::Error),
 }
 
-impl OSD {
-    pub fn new() -> OSD {
-        let mut config = Config::new("common");
-
-        // -1 means the default timeout of the notification server
-        let timeout = config.get("notification", "default timeout").unwrap_or(-1);
-
-        // Progress doesn't go down for the same notification, at least in mako, so disable it by default
-        let hint = config.get_default("progressbar", "use freedesktop notification hint", false);
-
-        let length = config.get_default("progressbar", "length", 20);
+/// Builds an [`OSD`]; see [`OSD::builder`]
+#[derive(Default)]
+pub struct OSDBuilder {
+    title: Option<String>,
+    icon: Option<String>,
+    contents: OSDContents,
+    urgency: Option<Urgency>,
+    timeout: Option<i32>,
+    style: Option<Style>,
+    control: Option<control::Handle>,
+    actions: Vec<(String, String)>,
+}
 
-        let full = config.get_default("progressbar", "full", String::from("█"));
-        let empty = config.get_default("progressbar", "empty", String::from("░"));
+impl OSDBuilder {
+    pub fn title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = Some(title.into());
+        self
+    }
 
-        let start = config.get_default("progressbar", "start", String::new());
-        let end = config.get_default("progressbar", "end", String::new());
+    pub fn icon<S: Into<String>>(mut self, icon: S) -> Self {
+        self.icon = Some(icon.into());
+        self
+    }
 
-        let notification = Notification::new();
+    pub fn contents(mut self, contents: OSDContents) -> Self {
+        self.contents = contents;
+        self
+    }
 
-        OSD {
-            title: None,
-            icon: None,
-            contents: OSDContents::default(),
-            urgency: Urgency::Normal,
-            id: Arc::new(Mutex::new(None)),
-            timeout,
-            hint,
-            length,
-            full,
-            empty,
-            start,
-            end,
-            notification,
-            on_close_handler: Arc::new(Mutex::new(Box::new(|_| {}))),
-        }
+    pub fn urgency(mut self, urgency: Urgency) -> Self {
+        self.urgency = Some(urgency);
+        self
     }
 
-    pub fn update(&mut self) -> Result<(), UpdateError> {
-        let text = match &self.contents {
-            OSDContents::Simple(text) => text.clone(),
-            OSDContents::Progress(value, text) => {
-                let mut s = String::new();
+    /// Override the timeout of the style, in milliseconds
+    pub fn timeout(mut self, timeout: i32) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-                if !self.hint {
-                    trace!("Hint is false, generating progressbar");
+    pub fn style(mut self, style: Style) -> Self {
+        self.style = Some(style);
+        self
+    }
 
-                    s.push_str(self.start.as_str());
+    /// Take the style from `config` rather than from the `common` configuration file
+    pub fn config(self, config: &mut Config) -> Self {
+        self.style(Style::load(config))
+    }
 
-                    for _ in 0..(value * self.length as f32) as i32 {
-                        s.push_str(self.full.as_str())
-                    }
+    /// Make the OSD respect `Suppress()` and report to `LastState` of a control object
+    pub fn control(mut self, control: control::Handle) -> Self {
+        self.control = Some(control);
+        self
+    }
 
-                    for _ in (value * self.length as f32) as i32..self.length {
-                        s.push_str(self.empty.as_str())
-                    }
+    /// Add a button; see [`OSD::on_action`]
+    pub fn action<S: Into<String>, L: Into<String>>(mut self, identifier: S, label: L) -> Self {
+        self.actions.push((identifier.into(), label.into()));
+        self
+    }
 
-                    s.push_str(self.end.as_str());
+    pub fn build(self) -> OSD {
+        let style = self
+            .style
+            .unwrap_or_else(|| Style::load(&mut Config::new("common")));
 
-                    s.push(' ');
-                }
+        OSD {
+            title: self.title,
+            icon: self.icon,
+            contents: self.contents,
+            urgency: self.urgency.unwrap_or(Urgency::Normal),
+            id: Arc::new(Mutex::new(None)),
+            timeout: self.timeout.unwrap_or(style.timeout),
+            control: self.control,
+            style,
+            actions: self.actions,
+            notification: Notification::new(),
+            on_close_handler: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_action_handler: Arc::new(Mutex::new(Box::new(|_| {}))),
+        }
+    }
+}
 
-                match text {
-                    OSDProgressText::Percentage => {
-                        s.push_str(((value * 100.) as i32).to_string().as_str());
+impl OSD {
+    /// An empty OSD styled according to the `common` configuration file
+    pub fn new() -> OSD {
+        OSD::builder().build()
+    }
 
-                        s.push('%');
-                    }
-                    OSDProgressText::Text(text) => {
-                        if let Some(text) = text.as_ref() {
-                            s.push_str(text.as_str())
-                        };
-                    }
-                }
+    pub fn builder() -> OSDBuilder {
+        OSDBuilder::default()
+    }
 
-                Some(s)
+    pub fn update(&mut self) -> Result<(), UpdateError> {
+        if let Some(control) = &self.control {
+            if control.suppressed() {
+                trace!("OSDs are suppressed, not showing {:?}", self.title);
+                return Ok(());
             }
+        }
+
+        let text = match &self.contents {
+            OSDContents::Simple(text) => text.clone(),
+            OSDContents::Progress(value, text) => Some(self.style.render(*value, text)),
         };
 
+        if let Some(control) = &self.control {
+            control.set_last_state(match (&self.title, &text) {
+                (Some(title), Some(text)) => format!("{}: {}", title, text),
+                (Some(s), None) | (None, Some(s)) => s.clone(),
+                (None, None) => String::new(),
+            });
+        }
+
         self.notification = Notification::new();
 
         let notification = self
@@ -161,34 +319,72 @@ impl OSD {
             .body(&text.unwrap_or_else(String::new))
             .icon(self.icon.as_deref().unwrap_or(""))
             .hint(Hint::Category("osd".to_owned()))
-            .urgency(self.urgency);
-        if self.hint {
+            .urgency(self.urgency)
+            .timeout(Timeout::from(self.timeout));
+        if self.style.hint {
             if let OSDContents::Progress(value, _) = self.contents {
                 let percentage = (value * 100.0).round() as i32;
                 notification.hint(Hint::CustomInt(String::from("value"), percentage));
             }
         }
-        if let Some(id) = *self.id.lock().unwrap() {
+        for (identifier, label) in self.actions.iter() {
+            notification.action(identifier, label);
+        }
+        let replaces = *self.id.lock().unwrap();
+        if let Some(id) = replaces {
             trace!("Replaces {}", id);
             notification.id(id);
         }
-        let handle: NotificationHandle = notification
-            .finalize()
-            .show()
-            .map_err(UpdateError::NotificationShowError)?;
+        let handle: NotificationHandle = match notification.finalize().show() {
+            Ok(handle) => handle,
+            // The notification server might have been restarted and forgotten about our
+            // notification, try once more with a new one
+            Err(err) if replaces.is_some() => {
+                debug!("Failed to replace notification {:?}: {}", replaces, err);
+                notification
+                    .id(0)
+                    .finalize()
+                    .show()
+                    .map_err(UpdateError::NotificationShowError)?
+            }
+            Err(err) => return Err(UpdateError::NotificationShowError(err)),
+        };
         trace!("Handle {:?}", handle);
-        self.id = Arc::new(Mutex::new(Some(handle.id())));
+        if let Some(control) = &self.control {
+            control.set_showing(true);
+        }
+        if Some(handle.id()) == replaces {
+            // Still the same notification, which is already being watched
+            return Ok(());
+        }
+        let handle_id = handle.id();
+        self.id = Arc::new(Mutex::new(Some(handle_id)));
         let id = self.id.clone();
         let on_close_handler = self.on_close_handler.clone();
+        let on_action_handler = self.on_action_handler.clone();
+        let control = self.control.clone();
         thread::spawn(move || {
-            handle.on_close(|reason| {
-                trace!("Notification has been closed, resetting id to None");
-                let mut id = id.lock().unwrap();
-                *id = None;
-                let mut on_close_handler = on_close_handler.lock().unwrap();
-                on_close_handler.call(reason);
-                *on_close_handler = Box::new(|_| {});
-            });
+            let mut closed = false;
+            while !closed {
+                handle_action(handle_id, |response: &ActionResponse| match response {
+                    ActionResponse::Custom(action) => {
+                        debug!("Action {} invoked on notification {}", action, handle_id);
+                        (on_action_handler.lock().unwrap())(action);
+                    }
+                    ActionResponse::Closed(reason) => {
+                        trace!("Notification has been closed, resetting id to None");
+                        closed = true;
+                        let mut id = id.lock().unwrap();
+                        *id = None;
+                        if let Some(control) = &control {
+                            control.set_showing(false);
+                        }
+                        let mut on_close_handler = on_close_handler.lock().unwrap();
+                        on_close_handler.call(*reason);
+                        *on_close_handler = Box::new(|_| {});
+                    }
+                });
+            }
         });
         Ok(())
     }
@@ -201,7 +397,7 @@ impl OSD {
     {
         if let Some(id) = *self.id.lock().unwrap() {
             trace!("Setting up a close callback on notification {}", id);
-            self.on_close_handler = Arc::new(Mutex::new(callback));
+            *self.on_close_handler.lock().unwrap() = callback;
             Ok(())
         } else {
             debug!("Notification is already closed, calling immediately");
@@ -209,6 +405,11 @@ impl OSD {
             Ok(())
         }
     }
+
+    /// Call `callback` with the identifier of every button of [`OSD::actions`] that gets clicked
+    pub fn on_action(&mut self, callback: ActionHandler) {
+        *self.on_action_handler.lock().unwrap() = callback;
+    }
 }
 
 impl Default for OSD {
@@ -216,3 +417,121 @@ impl Default for OSD {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod osd_tests {
+    use super::*;
+    use simple_osd_test_support::NotificationServer;
+    use std::time::{Duration, Instant};
+
+    fn osd() -> OSD {
+        OSD::builder()
+            .style(Style::default())
+            .title("Volume")
+            .contents(OSDContents::Progress(0.5, OSDProgressText::Percentage))
+            .build()
+    }
+
+    #[test]
+    fn replaces_previous_notification() {
+        let server = NotificationServer::start();
+        let mut osd = osd();
+        osd.update().unwrap();
+        osd.contents = OSDContents::Progress(0.6, OSDProgressText::Percentage);
+        osd.update().unwrap();
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].replaces_id, 0);
+        assert_eq!(notifications[1].replaces_id, notifications[0].id);
+        assert_eq!(notifications[1].summary, "Volume");
+        assert_eq!(notifications[1].body, "████████████░░░░░░░░ 60%");
+        assert_eq!(notifications[1].category.as_deref(), Some("osd"));
+    }
+
+    #[test]
+    fn critical_urgency() {
+        let server = NotificationServer::start();
+        let mut osd = OSD::builder()
+            .style(Style::default())
+            .urgency(Urgency::Critical)
+            .build();
+        osd.update().unwrap();
+        assert_eq!(server.notifications()[0].urgency, Some(2));
+    }
+
+    #[test]
+    fn suppressed_osd_is_not_shown() {
+        let server = NotificationServer::start();
+        let control = control::Handle::default();
+        let mut osd = osd();
+        osd.control = Some(control.clone());
+        control.suppress(Duration::from_secs(60));
+        osd.update().unwrap();
+        assert!(server.notifications().is_empty());
+    }
+
+    #[test]
+    fn action_is_reported() {
+        let server = NotificationServer::start();
+        let mut osd = OSD::builder()
+            .style(Style::default())
+            .action("snooze", "Snooze")
+            .build();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        osd.on_action(Box::new(move |action| sender.send(action.to_string()).unwrap()));
+        osd.update().unwrap();
+        // Updating the same notification doesn't add another listener
+        osd.update().unwrap();
+
+        let notification = &server.notifications()[1];
+        assert_eq!(
+            notification.actions,
+            vec![(String::from("snooze"), String::from("Snooze"))]
+        );
+        std::thread::sleep(Duration::from_millis(200));
+        server.invoke_action(notification.id, "snooze");
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "snooze"
+        );
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn dismissed_notification_is_not_replaced() {
+        let server = NotificationServer::start();
+        let mut osd = osd();
+        osd.update().unwrap();
+        // The close handler subscribes to NotificationClosed in the background
+        std::thread::sleep(Duration::from_millis(200));
+        server.dismiss(server.notifications()[0].id);
+
+        let started = Instant::now();
+        while osd.id.lock().unwrap().is_some() {
+            assert!(started.elapsed() < Duration::from_secs(5), "Dismissal not noticed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        osd.update().unwrap();
+        assert_eq!(server.notifications()[1].replaces_id, 0);
+    }
+
+    #[test]
+    fn showing_until_closed() {
+        let server = NotificationServer::start();
+        let control = control::Handle::default();
+        let mut osd = osd();
+        osd.control = Some(control.clone());
+        assert!(!control.showing());
+        osd.update().unwrap();
+        assert!(control.showing());
+
+        std::thread::sleep(Duration::from_millis(200));
+        server.dismiss(server.notifications()[0].id);
+        let started = Instant::now();
+        while control.showing() {
+            assert!(started.elapsed() < Duration::from_secs(5), "Dismissal not noticed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Restarting lost connections (pulseaudio, bluetoothd, D-Bus peers) with exponential backoff,
+//! instead of exiting and hoping that something restarts the daemon.
+
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::daemon::Heartbeat;
+
+/// Exponentially growing delay between reconnection attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Delay before the next attempt; every call doubles the following one, up to `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Run `f` until it returns `Ok`, restarting it with backoff whenever it fails.
+///
+/// `f` is expected to set up its connection from scratch, then block for as long as the
+/// connection lives. If it ran for longer than the maximum delay before failing, the backoff is
+/// reset, so that a connection which dropped after a day reconnects right away.
+pub fn supervise<F, T, E>(what: &str, backoff: &mut Backoff, mut f: F) -> T
+where
+    F: FnMut() -> Result<T, E>,
+    E: Display,
+{
+    loop {
+        let started = Instant::now();
+        match f() {
+            Ok(result) => return result,
+            Err(err) => {
+                if started.elapsed() > backoff.max {
+                    backoff.reset();
+                }
+                let delay = backoff.next_delay();
+                warn!("{} failed: {}; reconnecting in {:?}", what, err, delay);
+                // Waiting is what the daemon is supposed to do now, not a hang
+                Heartbeat::register().sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| b.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        b.reset();
+        assert_eq!(b.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn supervise_retries_until_success() {
+        let mut attempts = 0;
+        let mut b = Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+        let result = supervise("test", &mut b, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err("not yet")
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, 3);
+    }
+}
@@ -0,0 +1,219 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Minimal systemd integration: `sd_notify(3)`, the service watchdog and journald detection.
+//!
+//! Everything here is a no-op when the daemon is not started by systemd, so it is safe to call
+//! unconditionally.
+
+use std::env;
+use std::fs::metadata;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Send a raw `sd_notify` message, e.g. `READY=1`. Returns false if there's nobody to notify.
+pub fn notify(state: &str) -> bool {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    let addr = match path.strip_prefix('@') {
+        Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name.as_bytes()),
+        None => SocketAddr::from_pathname(&path),
+    };
+
+    let result = addr.and_then(|addr| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+
+    match result {
+        Ok(_) => {
+            trace!("Sent {:?} to {}", state, path);
+            true
+        }
+        Err(err) => {
+            warn!("Failed to notify systemd at {}: {}", path, err);
+            false
+        }
+    }
+}
+
+/// Tell systemd that startup is finished
+pub fn ready() -> bool {
+    notify("READY=1")
+}
+
+/// Set the free-form status shown in `systemctl --user status`
+pub fn status(status: &str) -> bool {
+    notify(&format!("STATUS={}", status))
+}
+
+/// Tell systemd that the daemon is shutting down
+pub fn stopping() -> bool {
+    notify("STOPPING=1")
+}
+
+/// Ping the service watchdog
+pub fn watchdog() -> bool {
+    notify("WATCHDOG=1")
+}
+
+fn for_this_process(var: &str) -> bool {
+    match env::var(var).ok().and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) => pid == process::id(),
+        // Not set means "whoever reads it"
+        None => true,
+    }
+}
+
+/// Watchdog interval requested by the service manager, if any
+pub fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Whether stderr is connected to the journal, so log lines can carry `<N>` priority prefixes
+pub fn journal_stream() -> bool {
+    let stream = match env::var("JOURNAL_STREAM") {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+
+    let mut parts = stream.splitn(2, ':');
+    let dev = parts.next().and_then(|d| d.parse::<u64>().ok());
+    let ino = parts.next().and_then(|i| i.parse::<u64>().ok());
+
+    match (dev, ino, metadata("/proc/self/fd/2")) {
+        (Some(dev), Some(ino), Ok(m)) => m.dev() == dev && m.ino() == ino,
+        _ => false,
+    }
+}
+
+/// The last beat of every loop registered with [`Heartbeat`]
+struct Beats {
+    next_id: usize,
+    last: Vec<(usize, Instant)>,
+    pinged: Option<Instant>,
+}
+
+impl Beats {
+    const fn new() -> Beats {
+        Beats {
+            next_id: 0,
+            last: Vec::new(),
+            pinged: None,
+        }
+    }
+
+    fn register(&mut self, now: Instant) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.last.push((id, now));
+        id
+    }
+
+    fn unregister(&mut self, id: usize) {
+        self.last.retain(|(i, _)| *i != id);
+    }
+
+    /// Record a beat of loop `id`; returns whether to ping the watchdog, which is the case when
+    /// every loop has beaten within half the `interval` and it hasn't been pinged for a quarter
+    fn beat(&mut self, id: usize, now: Instant, interval: Duration) -> bool {
+        if let Some((_, last)) = self.last.iter_mut().find(|(i, _)| *i == id) {
+            *last = now;
+        }
+        let alive = self
+            .last
+            .iter()
+            .all(|(_, last)| now.saturating_duration_since(*last) < interval / 2);
+        let due = self
+            .pinged
+            .is_none_or(|pinged| now.saturating_duration_since(pinged) >= interval / 4);
+        if alive && due {
+            self.pinged = Some(now);
+        }
+        alive && due
+    }
+}
+
+static BEATS: Mutex<Beats> = Mutex::new(Beats::new());
+
+/// An event loop the watchdog depends on.
+///
+/// The watchdog is only pinged while every registered loop keeps calling [`Heartbeat::beat`], so
+/// that one hung daemon gets the whole process restarted even if it shares it with others.
+pub struct Heartbeat {
+    id: usize,
+    interval: Duration,
+}
+
+impl Heartbeat {
+    /// Register a loop, if the service manager has asked for a watchdog
+    pub fn register() -> Option<Heartbeat> {
+        let interval = watchdog_interval()?;
+        let id = BEATS.lock().ok()?.register(Instant::now());
+        Some(Heartbeat { id, interval })
+    }
+
+    /// How often the loop has to beat
+    pub fn period(&self) -> Duration {
+        self.interval / 4
+    }
+
+    pub fn beat(&self) {
+        let ping = BEATS
+            .lock()
+            .map(|mut beats| beats.beat(self.id, Instant::now(), self.interval))
+            .unwrap_or(false);
+        if ping {
+            watchdog();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if let Ok(mut beats) = BEATS.lock() {
+            beats.unregister(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod systemd_tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_waits_for_every_loop() {
+        let interval = Duration::from_secs(20);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut beats = Beats::new();
+        let (a, b) = (beats.register(start), beats.register(start));
+
+        assert!(beats.beat(a, at(1), interval));
+        // Not due again until a quarter of the interval has passed
+        assert!(!beats.beat(b, at(2), interval));
+        assert!(beats.beat(b, at(6), interval));
+
+        // b hangs, so a can't keep the process alive on its own for long
+        assert!(beats.beat(a, at(11), interval));
+        assert!(!beats.beat(a, at(17), interval));
+
+        // Unless b is gone for good
+        beats.unregister(b);
+        assert!(beats.beat(a, at(18), interval));
+    }
+}
@@ -0,0 +1,156 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Kernel uevents, straight from netlink, which tell about devices (chargers, backlights, ...)
+//! being plugged in, out or changed as soon as it happens rather than on the next poll.
+//!
+//! [`UeventSocket`] can be registered with [`EventLoop::add_fd`](crate::event::EventLoop::add_fd),
+//! and [`UeventSocket::receive`] then called whenever it fires.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+/// Multicast group of the uevents sent by the kernel itself, rather than relayed by udev
+const KERNEL_GROUP: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uevent {
+    /// `add`, `remove`, `change` and so on
+    pub action: String,
+    pub devpath: String,
+    properties: HashMap<String, String>,
+}
+
+impl Uevent {
+    /// A message laid out as `action@devpath`, then `KEY=value` pairs, all separated by NULs
+    pub fn parse(message: &[u8]) -> Option<Uevent> {
+        let mut fields = message
+            .split(|&byte| byte == 0)
+            .map(String::from_utf8_lossy)
+            .filter(|field| !field.is_empty());
+        let header = fields.next()?;
+        let (action, devpath) = header.split_once('@')?;
+        Some(Uevent {
+            action: action.to_string(),
+            devpath: devpath.to_string(),
+            properties: fields
+                .filter_map(|field| {
+                    let (key, value) = field.split_once('=')?;
+                    Some((key.to_string(), value.to_string()))
+                })
+                .collect(),
+        })
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+
+    /// Such as `power_supply` or `backlight`
+    pub fn subsystem(&self) -> Option<&str> {
+        self.property("SUBSYSTEM")
+    }
+
+    /// The name of a `power_supply` device, as in `/sys/class/power_supply`
+    pub fn power_supply(&self) -> Option<&str> {
+        if self.subsystem()? != "power_supply" {
+            return None;
+        }
+        self.property("POWER_SUPPLY_NAME")
+            .or_else(|| self.devpath.rsplit('/').next())
+    }
+}
+
+/// A non-blocking socket receiving the uevents of the kernel
+pub struct UeventSocket {
+    fd: OwnedFd,
+}
+
+impl UeventSocket {
+    pub fn open() -> io::Result<UeventSocket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_GROUP;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UeventSocket { fd })
+    }
+
+    /// Every uevent received so far, without blocking
+    pub fn receive(&self) -> io::Result<Vec<Uevent>> {
+        let mut uevents = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let received = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(uevents),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(err),
+                };
+            }
+            uevents.extend(Uevent::parse(&buffer[..received as usize]));
+        }
+    }
+}
+
+impl AsFd for UeventSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod uevent_tests {
+    use super::*;
+
+    #[test]
+    fn parses_power_supply_changes() {
+        let message = b"change@/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC\0\
+            ACTION=change\0\
+            DEVPATH=/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC\0\
+            SUBSYSTEM=power_supply\0\
+            POWER_SUPPLY_NAME=AC\0\
+            POWER_SUPPLY_ONLINE=1\0\
+            SEQNUM=4242\0";
+        let uevent = Uevent::parse(message).unwrap();
+        assert_eq!(uevent.action, "change");
+        assert_eq!(uevent.subsystem(), Some("power_supply"));
+        assert_eq!(uevent.power_supply(), Some("AC"));
+        assert_eq!(uevent.property("POWER_SUPPLY_ONLINE"), Some("1"));
+
+        let uevent = Uevent::parse(b"add@/devices/platform/serial8250\0SUBSYSTEM=platform\0");
+        assert_eq!(uevent.unwrap().power_supply(), None);
+        assert_eq!(Uevent::parse(b"libudev\0SUBSYSTEM=power_supply\0"), None);
+    }
+}
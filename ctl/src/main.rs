@@ -0,0 +1,266 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+extern crate simple_osd_common as osd;
+extern crate thiserror;
+#[macro_use]
+extern crate log;
+
+use std::env;
+use std::process::exit;
+
+use osd::control::{self, ControlError};
+use osd::daemon::DAEMONS;
+use osd::notify::{OSDContents, OSDProgressText, Urgency, OSD};
+use thiserror::Error;
+
+static USAGE: &str = "Usage:
+  simple-osd-ctl show DAEMON
+  simple-osd-ctl suppress DAEMON DURATION
+  simple-osd-ctl reload DAEMON
+  simple-osd-ctl status [--json]
+  simple-osd-ctl notify [--title TITLE] [--icon ICON] [--progress VALUE]
+                        [--urgency low|normal|critical] [TEXT]
+
+DAEMON is one of battery, brightness, mpris (or media), pulseaudio (or volume) and bluetooth.
+DURATION is a number of seconds, optionally followed by s, m or h.";
+
+#[derive(Error, Debug)]
+enum CtlError {
+    #[error("{0}")]
+    Usage(String),
+    #[error("{0} is not running")]
+    NotRunning(String),
+    #[error("{0}")]
+    Control(#[from] ControlError),
+    #[error("Call to {0} failed: {1}")]
+    Call(String, zbus::Error),
+    #[error("Failed to show the notification: {0}")]
+    OSDUpdate(#[from] osd::notify::UpdateError),
+}
+
+/// `volume` -> `pulseaudio`; the names people think of rather than the backends
+fn daemon_name(name: &str) -> Result<&str, CtlError> {
+    let name = match name {
+        "volume" => "pulseaudio",
+        "media" => "mpris",
+        _ => name,
+    };
+    DAEMONS
+        .iter()
+        .find(|daemon| **daemon == name)
+        .copied()
+        .ok_or_else(|| CtlError::Usage(format!("Unknown daemon {}", name)))
+}
+
+/// `10`, `10s`, `5m` or `1h` as seconds
+fn parse_duration(duration: &str) -> Option<u32> {
+    let (number, unit) = match duration.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&duration[..i], c),
+        _ => (duration, 's'),
+    };
+    let number: u32 = number.parse().ok()?;
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod parse_duration_tests {
+    use super::parse_duration;
+    #[test]
+    fn bare_seconds() {
+        assert_eq!(parse_duration("10"), Some(10));
+    }
+    #[test]
+    fn units() {
+        assert_eq!(parse_duration("10s"), Some(10));
+        assert_eq!(parse_duration("5m"), Some(300));
+        assert_eq!(parse_duration("1h"), Some(3600));
+    }
+    #[test]
+    fn fails_on_garbage() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10d"), None);
+        assert_eq!(parse_duration("-10s"), None);
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod json_string_tests {
+    use super::json_string;
+    #[test]
+    fn escapes() {
+        assert_eq!(json_string("Volume: \"50%\"\n"), r#""Volume: \"50%\"\n""#);
+        assert_eq!(json_string("a\\b\tc"), r#""a\\b\u0009c""#);
+    }
+}
+
+fn call<F>(daemon: &str, f: F) -> Result<(), CtlError>
+where
+    F: FnOnce(&control::DaemonProxy) -> zbus::Result<()>,
+{
+    // A daemon with a D-Bus activation file is started by the call
+    if !control::is_exported(daemon)? && !control::is_activatable(daemon)? {
+        return Err(CtlError::NotRunning(daemon.to_string()));
+    }
+    f(&control::proxy(daemon)?).map_err(|err| CtlError::Call(control::bus_name(daemon), err))
+}
+
+fn status(json: bool) -> Result<(), CtlError> {
+    let mut entries = Vec::new();
+    for daemon in DAEMONS {
+        let last_state = if control::is_exported(daemon)? {
+            let state = control::proxy(daemon)?
+                .last_state()
+                .map_err(|err| CtlError::Call(control::bus_name(daemon), err))?;
+            Some(state)
+        } else {
+            None
+        };
+        entries.push((daemon, last_state));
+    }
+
+    if json {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(daemon, last_state)| {
+                format!(
+                    "{{\"daemon\":{},\"running\":{},\"last_state\":{}}}",
+                    json_string(daemon),
+                    last_state.is_some(),
+                    last_state.as_deref().map_or(String::from("null"), json_string)
+                )
+            })
+            .collect();
+        println!("[{}]", entries.join(","));
+    } else {
+        for (daemon, last_state) in entries {
+            match last_state {
+                Some(state) => println!("{:<12}running  {}", daemon, state),
+                None => println!("{:<12}stopped", daemon),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Show a one-off OSD, styled according to the `common` configuration like the daemons' ones
+fn notify(args: &[String]) -> Result<(), CtlError> {
+    let mut osd = OSD::new();
+    let mut progress = None;
+    let mut text = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| CtlError::Usage(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--title" => osd.title = Some(value()?),
+            "--icon" => osd.icon = Some(value()?),
+            "--progress" => {
+                let v = value()?;
+                progress = match v.parse::<f32>() {
+                    Ok(p) if (0.0..=1.0).contains(&p) => Some(p),
+                    _ => {
+                        return Err(CtlError::Usage(format!(
+                            "Progress must be between 0 and 1, not {}",
+                            v
+                        )))
+                    }
+                }
+            }
+            "--urgency" => {
+                osd.urgency = match value()?.as_str() {
+                    "low" => Urgency::Low,
+                    "normal" => Urgency::Normal,
+                    "critical" => Urgency::Critical,
+                    other => return Err(CtlError::Usage(format!("Unknown urgency {}", other))),
+                }
+            }
+            _ if arg.starts_with("--") => {
+                return Err(CtlError::Usage(format!("Unknown option {}", arg)))
+            }
+            _ if text.is_none() => text = Some(arg.clone()),
+            _ => return Err(CtlError::Usage(String::from("Too many arguments"))),
+        }
+    }
+
+    osd.contents = match progress {
+        Some(p) if text.is_some() => OSDContents::Progress(p, OSDProgressText::Text(text)),
+        Some(p) => OSDContents::Progress(p, OSDProgressText::Percentage),
+        None => OSDContents::Simple(text),
+    };
+    osd.update()?;
+    Ok(())
+}
+
+fn ctl(args: &[String]) -> Result<(), CtlError> {
+    let wrong_arguments = || CtlError::Usage(String::from("Wrong number of arguments"));
+    match args {
+        [command, daemon] if command == "show" => {
+            call(daemon_name(daemon)?, |proxy| proxy.show_current())
+        }
+        [command, daemon] if command == "reload" => {
+            call(daemon_name(daemon)?, |proxy| proxy.reload())
+        }
+        [command, daemon, duration] if command == "suppress" => {
+            let seconds = parse_duration(duration)
+                .ok_or_else(|| CtlError::Usage(format!("Invalid duration {}", duration)))?;
+            call(daemon_name(daemon)?, |proxy| proxy.suppress(seconds))
+        }
+        [command] if command == "status" => status(false),
+        [command, json] if command == "status" && json == "--json" => status(true),
+        [command, rest @ ..] if command == "notify" => notify(rest),
+        [command, ..] if ["show", "reload", "suppress", "status"].contains(&command.as_str()) => {
+            Err(wrong_arguments())
+        }
+        [command, ..] => Err(CtlError::Usage(format!("Unknown command {}", command))),
+        [] => Err(CtlError::Usage(String::from("No command given"))),
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some("--help") | Some("-h") = args.first().map(String::as_str) {
+        println!("{}", USAGE);
+        return;
+    }
+
+    match ctl(&args) {
+        Ok(()) => {}
+        Err(CtlError::Usage(err)) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            exit(2)
+        }
+        Err(err) => {
+            error!("{}", err);
+            exit(1)
+        }
+    }
+}
@@ -24,47 +24,53 @@
       system:
       let
         pkgs = nixpkgs.legacyPackages.${system};
+
+        commonDeps = with pkgs; [
+          libnotify
+          gdk-pixbuf
+          glib
+        ];
+
+        inherit (import "${crate2nix}/tools.nix" { inherit pkgs; })
+          generatedCargoNix
+          ;
+
+        project =
+          pkgs.callPackage
+            (generatedCargoNix {
+              name = "simple-osd-daemons";
+              src = ./.;
+            })
+            {
+              defaultCrateOverrides = pkgs.defaultCrateOverrides // {
+                simple-osd-battery = oa: { buildInputs = commonDeps; };
+                simple-osd-common = oa: {
+                  buildInputs = commonDeps ++ [
+                    pkgs.dbus.lib
+                    pkgs.dbus.dev
+                  ];
+                  nativeBuildInputs = [ pkgs.pkg-config ];
+                };
+                simple-osd-brightness = oa: { buildInputs = commonDeps; };
+                simple-osd-pulseaudio = oa: {
+                  buildInputs = commonDeps ++ [ pkgs.libpulseaudio ];
+                  postInstall = "patchelf --add-rpath ${pkgs.libpulseaudio}/lib $out/bin/*";
+                };
+                simple-osd-mpris = oa: {
+                  buildInputs = commonDeps ++ [ pkgs.libpulseaudio ];
+                  postInstall = "patchelf --add-rpath ${pkgs.libpulseaudio}/lib $out/bin/*";
+                };
+                simple-osd-ctl = oa: { buildInputs = commonDeps; };
+                simple-osd = oa: {
+                  buildInputs = commonDeps ++ [ pkgs.libpulseaudio ];
+                  postInstall = "patchelf --add-rpath ${pkgs.libpulseaudio}/lib $out/bin/*";
+                };
+              };
+            };
       in
       {
         packages =
           let
-            commonDeps = with pkgs; [
-              libnotify
-              gdk-pixbuf
-              glib
-            ];
-
-            inherit (import "${crate2nix}/tools.nix" { inherit pkgs; })
-              generatedCargoNix
-              ;
-
-            project =
-              pkgs.callPackage
-                (generatedCargoNix {
-                  name = "simple-osd-daemons";
-                  src = ./.;
-                })
-                {
-                  defaultCrateOverrides = pkgs.defaultCrateOverrides // {
-                    simple-osd-battery = oa: { buildInputs = commonDeps; };
-                    simple-osd-common = oa: {
-                      buildInputs = commonDeps ++ [
-                        pkgs.dbus.lib
-                        pkgs.dbus.dev
-                      ];
-                      nativeBuildInputs = [ pkgs.pkg-config ];
-                    };
-                    simple-osd-brightness = oa: { buildInputs = commonDeps; };
-                    simple-osd-pulseaudio = oa: {
-                      buildInputs = commonDeps ++ [ pkgs.libpulseaudio ];
-                      postInstall = "patchelf --add-rpath ${pkgs.libpulseaudio}/lib $out/bin/*";
-                    };
-                    simple-osd-mpris = oa: {
-                      buildInputs = commonDeps ++ [ pkgs.libpulseaudio ];
-                      postInstall = "patchelf --add-rpath ${pkgs.libpulseaudio}/lib $out/bin/*";
-                    };
-                  };
-                };
             membersList = builtins.attrValues (
               builtins.mapAttrs (name: member: {
                 name = pkgs.lib.removePrefix "simple-osd-" name;
@@ -80,12 +86,22 @@
             };
           };
 
+        # The tests start a private dbus-daemon
+        checks = builtins.mapAttrs (
+          name: member:
+          member.build.override {
+            runTests = true;
+            testInputs = [ pkgs.dbus ];
+          }
+        ) project.workspaceMembers;
+
         devShells.default = pkgs.mkShell {
           inputsFrom = builtins.attrValues self.packages.${system};
           buildInputs = [
             pkgs.cargo
             pkgs.rust-analyzer
             pkgs.clippy
+            pkgs.dbus
           ];
         };
       }
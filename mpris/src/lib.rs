@@ -0,0 +1,497 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+pub extern crate mpris;
+pub extern crate simple_osd_common as osd;
+#[macro_use]
+pub extern crate log;
+extern crate zbus;
+
+pub use osd::config::Config;
+use osd::daemon::{is_running, ready};
+use osd::control::{is_showing, Command, Control};
+use osd::event::{EventLoop, EventSender};
+use osd::supervisor::{supervise, Backoff};
+pub use osd::notify::{OSDContents, OSDProgressText, OSD};
+
+use mpris::{FindingError, PlaybackStatus, Player, PlayerFinder};
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::Type as MessageType;
+use zbus::MatchRule;
+
+pub use std::time::{Duration, Instant};
+
+use std::vec::Vec;
+
+use thiserror::Error;
+
+/// How long the pulseaudio daemon of the same process gets to show a volume change, after which
+/// the player is shown instead if it didn't
+const VOLUME_SETTLE_TIME: Duration = Duration::from_millis(250);
+
+fn format_duration(d: Duration) -> String {
+    let s = d.as_secs();
+    let secs = s % 60;
+    let mins = s / 60;
+    format!("{:02}:{:02}", mins, secs)
+}
+
+#[cfg(test)]
+mod format_duration_tests {
+    use super::*;
+    #[test]
+    fn seconds() {
+        assert_eq!(&format_duration(Duration::from_secs(10)), "00:10");
+    }
+    #[test]
+    fn minutes_seconds() {
+        assert_eq!(&format_duration(Duration::from_secs(70)), "01:10");
+    }
+    #[test]
+    fn double_digit_minutes_seconds() {
+        assert_eq!(&format_duration(Duration::from_secs(810)), "13:30");
+    }
+    #[test]
+    fn triple_digit_minutes_seconds() {
+        assert_eq!(&format_duration(Duration::from_secs(7210)), "120:10");
+    }
+}
+
+fn format_artists(artists: Vec<&str>) -> Option<String> {
+    let mut v = artists.clone();
+    v.reverse();
+
+    if v.len() < 2 {
+        return Some(v.pop()?.to_string());
+    }
+
+    let mut s = String::new();
+
+    for _ in 0..v.len() - 2 {
+        s.push_str(v.pop()?);
+        s.push_str(", ")
+    }
+
+    s.push_str(v.pop()?);
+
+    s.push_str(" & ");
+
+    s.push_str(v.pop()?);
+
+    Some(s)
+}
+
+#[cfg(test)]
+mod format_artists_test {
+    use super::*;
+    #[test]
+    fn none() {
+        assert_eq!(format_artists([].to_vec()), None);
+    }
+    #[test]
+    fn one() {
+        assert_eq!(
+            format_artists(["John Doe"].to_vec()),
+            Some("John Doe".to_string())
+        );
+    }
+    #[test]
+    fn two() {
+        assert_eq!(
+            format_artists(["John Doe", "Jane Doe"].to_vec()),
+            Some("John Doe & Jane Doe".to_string())
+        );
+    }
+    #[test]
+    fn many() {
+        assert_eq!(
+            format_artists(["John Doe", "Jane Doe", "Chris P. Bacon", "Seymore Clevarge"].to_vec()),
+            Some("John Doe, Jane Doe, Chris P. Bacon & Seymore Clevarge".to_string())
+        );
+    }
+}
+
+#[cfg(feature = "display_on_volume_changes")]
+mod volume_changes {
+    extern crate libpulse_binding as pulse;
+
+    use super::*;
+
+    use pulse::context::subscribe::{Facility, InterestMaskSet, Operation};
+    use pulse::context::{Context, FlagSet, State};
+    use pulse::mainloop::standard::{IterateResult, Mainloop};
+
+    /// Run a pulseaudio mainloop in its own thread, sending `Event::Volume` on every sink change
+    pub(super) fn spawn(server: Option<String>, sender: EventSender<Event>) {
+        std::thread::spawn(move || {
+            supervise("Pulseaudio connection", &mut Backoff::default(), || {
+                monitor(server.as_deref(), &sender)
+            })
+        });
+    }
+
+    fn monitor(server: Option<&str>, sender: &EventSender<Event>) -> Result<(), String> {
+        let mut mainloop = Mainloop::new().ok_or("Failed to create mainloop")?;
+
+        let mut context =
+            Context::new(&mainloop, osd::APPNAME).ok_or("Failed to create new context")?;
+
+        context
+            .connect(server, FlagSet::all(), None)
+            .map_err(|err| format!("Failed to connect context: {}", err))?;
+
+        // Wait for context to be ready
+        loop {
+            match mainloop.iterate(true) {
+                IterateResult::Quit(_) | IterateResult::Err(_) => {
+                    return Err("Iterate state was not success".to_string());
+                }
+                IterateResult::Success(_) => {}
+            }
+            match context.get_state() {
+                State::Ready => {
+                    break;
+                }
+                State::Failed | State::Unconnected | State::Terminated => {
+                    return Err("Context state failed/terminated".to_string());
+                }
+                _ => {}
+            }
+        }
+
+        context.subscribe(InterestMaskSet::SINK, |success| {
+            if !success {
+                error!("Failed to subscribe to pulseaudio events");
+            }
+        });
+
+        let volume_sender = sender.clone();
+        let subscribe_callback = move |facility, operation, _index| {
+            if facility == Some(Facility::Sink) && operation == Some(Operation::Changed) {
+                volume_sender.send(Event::Volume);
+            }
+        };
+
+        context.set_subscribe_callback(Some(Box::new(subscribe_callback)));
+
+        loop {
+            match mainloop.iterate(true) {
+                IterateResult::Quit(_) | IterateResult::Err(_) => {
+                    return Err("Iterate state was not success".to_string());
+                }
+                IterateResult::Success(_) => {}
+            }
+            if context.get_state() != State::Ready {
+                return Err("Pulseaudio context is no longer ready".to_string());
+            }
+        }
+    }
+}
+
+/// Everything we show about the current track, captured at `at`
+#[derive(Clone, Debug)]
+struct Track {
+    title: String,
+    artists: String,
+    status: PlaybackStatus,
+    length: Option<Duration>,
+    position: Duration,
+    rate: f64,
+    at: Instant,
+}
+
+impl Track {
+    fn from_player(player: &Player) -> Result<Track, mpris::DBusError> {
+        let metadata = player.get_metadata()?;
+        Ok(Track {
+            title: metadata.title().unwrap_or("Unknown").to_string(),
+            artists: metadata
+                .artists()
+                .and_then(format_artists)
+                .unwrap_or_else(|| "Unknown".to_string()),
+            status: player.get_playback_status()?,
+            length: metadata.length(),
+            position: player.get_position().unwrap_or_default(),
+            rate: player.get_playback_rate().unwrap_or(1.),
+            at: Instant::now(),
+        })
+    }
+
+    /// Current position, extrapolated from the last known one
+    fn position(&self) -> Duration {
+        if self.status == PlaybackStatus::Playing {
+            self.position + self.at.elapsed().mul_f64(self.rate.max(0.))
+        } else {
+            self.position
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Event {
+    /// The active player changed state, or went away
+    Player(Option<Track>),
+    /// Volume of some sink changed
+    #[cfg_attr(not(feature = "display_on_volume_changes"), allow(dead_code))]
+    Volume,
+    /// The pulseaudio daemon of the same process had the time to show the last volume change
+    VolumeSettled,
+    /// The user has closed the notification
+    Dismissed,
+    /// Time to redraw the progress bar
+    Refresh,
+    Control(Command),
+}
+
+struct Settings {
+    update_on_volume_change: bool,
+    timeout: Duration,
+}
+
+impl Settings {
+    fn load(config: &mut Config) -> Settings {
+        Settings {
+            update_on_volume_change: config.get_default("default", "update on volume change", true),
+            timeout: Duration::from_secs(
+                config.get_default("default", "notification display time", 5),
+            ),
+        }
+    }
+}
+
+/// Follow the active player in a separate thread, since the mpris crate is blocking
+fn watch_players(sender: EventSender<Event>) {
+    std::thread::spawn(move || {
+        supervise("D-Bus connection", &mut Backoff::default(), || {
+            follow_active_player(&sender)
+        })
+    });
+}
+
+/// Players showing up on the session bus, or leaving it
+fn watch_player_names() -> zbus::Result<MessageIterator> {
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build();
+    MessageIterator::for_match_rule(rule, &Connection::session()?, None)
+}
+
+/// Report the state of whichever player is active, switching players when one goes away.
+///
+/// Only returns when the D-Bus connection can't be set up or is lost, or the event loop is gone.
+fn follow_active_player(sender: &EventSender<Event>) -> Result<(), MprisError> {
+    // Before looking for players, so that none is missed in between
+    let mut names = watch_player_names().map_err(MprisError::WatchPlayers)?;
+    let mut wait_for_players = || match names.next() {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => Err(MprisError::WatchPlayers(err)),
+        None => Err(MprisError::ConnectionClosed),
+    };
+    let player_finder = PlayerFinder::new().map_err(MprisError::PlayerFinderNew)?;
+
+    loop {
+        let player = match player_finder.find_active() {
+            Ok(player) => player,
+            Err(FindingError::NoPlayerFound) => {
+                trace!("Player not found, waiting for one to show up");
+                wait_for_players()?;
+                continue;
+            }
+            Err(FindingError::DBusError(err)) => return Err(MprisError::PlayerFind(err)),
+        };
+        debug!(player = player.identity(); "Found a new player");
+
+        let events = match player.events() {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("{}", MprisError::PlayerEvents(err));
+                // The player is likely still active, so finding it again right away would spin
+                wait_for_players()?;
+                continue;
+            }
+        };
+
+        if !sender.send(Event::Player(Track::from_player(&player).ok())) {
+            return Ok(());
+        }
+
+        for event in events {
+            match event {
+                Ok(mpris::Event::PlayerShutDown) => break,
+                Ok(event) => trace!("Player event {:?}", event),
+                Err(err) => {
+                    warn!("Failed to get player events: {}", err);
+                    break;
+                }
+            }
+            if !sender.send(Event::Player(Track::from_player(&player).ok())) {
+                return Ok(());
+            }
+        }
+
+        debug!(player = player.identity(); "Player stopped running, looking for a new one");
+        if !sender.send(Event::Player(None)) {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MprisError {
+    #[error("Unable to create a player finder: {0}")]
+    PlayerFinderNew(mpris::DBusError),
+    #[error("Unable to find an active player: {0}")]
+    PlayerFind(mpris::DBusError),
+    #[error("Unable to get player events: {0}")]
+    PlayerEvents(mpris::DBusError),
+    #[error("Unable to watch for players: {0}")]
+    WatchPlayers(zbus::Error),
+    #[error("The D-Bus connection has been closed")]
+    ConnectionClosed,
+    #[error("Failed to set a notification close callback: {0}")]
+    OSDOnClose(#[from] osd::notify::CloseCallbackError),
+    #[error("Event loop failed: {0}")]
+    EventLoop(#[from] std::io::Error),
+}
+
+pub fn daemon_mpris() -> Result<(), MprisError> {
+    let mut config = Config::new("mpris");
+    let mut osd = OSD::new();
+    let mut waiting_on_close = false;
+    let mut dismissed = false;
+
+    let mut settings = Settings::load(&mut config);
+    let refresh_interval = Duration::from_millis(500);
+
+    let mut events = EventLoop::new()?;
+
+    #[cfg(feature = "display_on_volume_changes")]
+    let mut watching_volume = false;
+    #[cfg(feature = "display_on_volume_changes")]
+    if settings.update_on_volume_change {
+        volume_changes::spawn(config.get::<String>("pulseaudio", "server"), events.sender());
+        watching_volume = true;
+    }
+
+    watch_players(events.sender());
+
+    let sender = events.sender();
+    let control = Control::new_or_warn("mpris", move |command| {
+        sender.send(Event::Control(command));
+    });
+    osd.control = Some(control.handle());
+
+    let refresh = events.add_timer(refresh_interval, Event::Refresh);
+    events.set_timer(refresh, None);
+    let volume_settled = events.add_oneshot(VOLUME_SETTLE_TIME, Event::VolumeSettled);
+    events.set_timer(volume_settled, None);
+
+    ready();
+
+    let mut track: Option<Track> = None;
+    let mut shown_at: Option<Instant> = None;
+
+    loop {
+        match events.wait()? {
+            Event::Player(new_track) => {
+                let changed = match (&track, &new_track) {
+                    (Some(old), Some(new)) => old.title != new.title || old.status != new.status,
+                    (None, None) => false,
+                    _ => true,
+                };
+                track = new_track;
+                if changed {
+                    shown_at = Some(Instant::now());
+                    dismissed = false;
+                }
+            }
+            Event::Volume if settings.update_on_volume_change && is_running("pulseaudio") => {
+                events.set_timer(volume_settled, Some(VOLUME_SETTLE_TIME));
+            }
+            // Don't show the same change twice
+            Event::Volume | Event::VolumeSettled
+                if settings.update_on_volume_change && !is_showing("pulseaudio") =>
+            {
+                shown_at = Some(Instant::now());
+                dismissed = false;
+            }
+            Event::Volume | Event::VolumeSettled => {}
+            Event::Control(Command::ShowCurrent) => {
+                shown_at = Some(Instant::now());
+                dismissed = false;
+            }
+            Event::Control(Command::Reload) => {
+                info!("Reloading configuration");
+                config = Config::new("mpris");
+                settings = Settings::load(&mut config);
+                #[cfg(feature = "display_on_volume_changes")]
+                if settings.update_on_volume_change && !watching_volume {
+                    volume_changes::spawn(
+                        config.get::<String>("pulseaudio", "server"),
+                        events.sender(),
+                    );
+                    watching_volume = true;
+                }
+            }
+            Event::Dismissed => {
+                trace!("Notification closed, flagging it as dismissed");
+                dismissed = true;
+            }
+            Event::Refresh => {}
+        }
+
+        let visible = match (&track, shown_at) {
+            (Some(track), Some(shown_at)) => {
+                shown_at.elapsed() < settings.timeout
+                    && track.status != PlaybackStatus::Stopped
+                    && !dismissed
+            }
+            _ => false,
+        };
+
+        if let (true, Some(track)) = (visible, &track) {
+            osd.title = Some(format!("{:?}: {} - {}", track.status, track.title, track.artists));
+
+            osd.contents = match track.length {
+                Some(length) => {
+                    let position = track.position().min(length);
+
+                    let ratio = position.as_secs_f32() / length.as_secs_f32();
+                    let text = format!(
+                        "{} / {}",
+                        format_duration(position),
+                        format_duration(length)
+                    );
+                    OSDContents::Progress(ratio, OSDProgressText::Text(Some(text)))
+                }
+                None => {
+                    trace!("No track length provided");
+                    OSDContents::Simple(None)
+                }
+            };
+            osd.timeout = 1000;
+            osd.icon = match track.status {
+                PlaybackStatus::Playing => Some("media-playback-start".to_string()),
+                PlaybackStatus::Paused => Some("media-playback-pause".to_string()),
+                _ => None,
+            };
+            osd.update_();
+            if !waiting_on_close {
+                trace!("Setting up a notification dismissal callback");
+                waiting_on_close = true;
+                let sender = events.sender();
+                osd.on_close(Box::new(move |_| {
+                    sender.send(Event::Dismissed);
+                }))?;
+            }
+            events.set_timer(refresh, Some(refresh_interval));
+        } else {
+            waiting_on_close = false;
+            events.set_timer(refresh, None);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+extern crate libpulse_binding as pulse;
+
+extern crate simple_osd_common as osd;
+
+#[macro_use]
+extern crate log;
+
+use std::cell::{Cell, RefCell};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use std::collections::HashMap;
+
+use osd::config::Config;
+use osd::control::{Command, Control};
+use osd::daemon::{ready, Heartbeat};
+use osd::supervisor::{supervise, Backoff};
+use osd::notify::{OSDContents, OSDProgressText, OSD};
+use pulse::context::{Context, FlagSet, State};
+use pulse::error::PAErr;
+use pulse::mainloop::api::Mainloop as _;
+use pulse::mainloop::events::io::FlagSet as IoEventFlagSet;
+use pulse::mainloop::standard::Mainloop;
+use pulse::time::MicroSeconds;
+
+use pulse::context::subscribe::{InterestMaskSet, Facility, Operation};
+
+use pulse::callbacks::ListResult;
+use pulse::context::introspect::SinkInfo;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PulseaudioError {
+    #[error("Failed to create a pulseaudio mainloop")]
+    MainloopNewError,
+    #[error("Failed to create a pulseaudio context")]
+    ContextNewError,
+    #[error("Failed to connect a pulseaudio context: {0:?}")]
+    ContextConnectError(pulse::error::PAErr),
+    #[error("Pulseaudio context state failed/terminated")]
+    ContextStateError,
+    #[error("Pulseaudio mainloop exited with an error: {0}")]
+    MainloopRunErr(pulse::error::PAErr),
+    #[error("Pulseaudio mainloop has quit")]
+    MainloopQuit,
+    #[error("Failed to set up the control socket: {0}")]
+    ControlPipeError(std::io::Error),
+    #[error("Failed to watch the control socket")]
+    ControlEventError,
+}
+
+type SinkStates = Rc<RefCell<HashMap<String, (f32, bool)>>>;
+
+/// Commands from the control object, and a socket to wake up the pulseaudio mainloop with
+struct Commands {
+    receiver: Receiver<Command>,
+    wakeup: UnixStream,
+}
+
+pub fn pulseaudio_daemon() -> Result<(), PulseaudioError> {
+    // Kept across reconnections, so that a server restart doesn't show every sink again
+    let osd = Rc::new(RefCell::new(OSD::new()));
+    let prev_state: SinkStates = Rc::new(RefCell::new(HashMap::new()));
+
+    let (sender, receiver) = channel();
+    let (wakeup_sender, wakeup) = UnixStream::pair().map_err(PulseaudioError::ControlPipeError)?;
+    wakeup
+        .set_nonblocking(true)
+        .map_err(PulseaudioError::ControlPipeError)?;
+    let commands = Rc::new(Commands { receiver, wakeup });
+
+    let control = Control::new_or_warn("pulseaudio", move |command| {
+        if sender.send(command).is_ok() {
+            let _ = (&wakeup_sender).write_all(&[0]);
+        }
+    });
+    osd.borrow_mut().control = Some(control.handle());
+
+    // Every iteration (re)reads the configuration
+    loop {
+        let mut config = Config::new("pulseaudio");
+        let server = config.get::<String>("default", "server");
+
+        supervise("Pulseaudio connection", &mut Backoff::default(), || {
+            watch_sinks(
+                server.as_deref(),
+                osd.clone(),
+                prev_state.clone(),
+                commands.clone(),
+            )
+        });
+
+        info!("Reloading configuration");
+    }
+}
+
+/// Show the volume of a sink if it has changed since the last time (or if `force` is set)
+fn show_volume(
+    osd: &mut OSD,
+    prev_state: &SinkStates,
+    sink_name: &str,
+    volume: f32,
+    mute: bool,
+    force: bool,
+) {
+    let show = if let Some((volume_prev, mute_prev)) = prev_state.borrow_mut().insert(sink_name.to_string(), (volume, mute)) {
+        volume_prev != volume || mute_prev != mute
+    } else { true };
+    if show || force {
+        let muted_message = if mute { " [MUTED]" } else { "" };
+        osd.icon = Some(String::from(match (mute, volume) {
+            (true, _) => "audio-volume-muted",
+            (false, v) if v < 0.33 => "audio-volume-low",
+            (false, v) if v < 0.66 => "audio-volume-medium",
+            (false, _) => "audio-volume-high",
+        }));
+        osd.title = Some(format!("Volume on {}{}", sink_name, muted_message));
+        osd.contents = OSDContents::Progress(volume, OSDProgressText::Percentage);
+        osd.update_();
+    }
+}
+
+/// Show the sink volume if it has changed since we've last seen it, or if `force` is set
+fn sink_info_handler(
+    osd: Rc<RefCell<OSD>>,
+    prev_state: SinkStates,
+    force: bool,
+) -> impl FnMut(ListResult<&SinkInfo>) + Clone {
+    move |results: ListResult<&SinkInfo>| {
+        if let ListResult::Item(i) = results {
+            let volume = i.volume.avg().0 as f32 / 65536.;
+
+            let sink_name = i.description.as_deref().unwrap_or("Unnamed sink");
+            let sink = i.name.as_deref().unwrap_or_default();
+            debug!(sink; "Volume {:.0}%, muted: {}", volume * 100., i.mute);
+            show_volume(&mut osd.borrow_mut(), &prev_state, sink_name, volume, i.mute, force);
+        }
+    }
+}
+
+/// Connect to the server and show sink changes for as long as the connection is alive.
+///
+/// Returns `Ok` when asked to reload the configuration.
+fn watch_sinks(
+    server: Option<&str>,
+    osd: Rc<RefCell<OSD>>,
+    prev_state: SinkStates,
+    commands: Rc<Commands>,
+) -> Result<(), PulseaudioError> {
+    let heartbeat = Heartbeat::register();
+    let mut mainloop = Mainloop::new().ok_or(PulseaudioError::MainloopNewError)?;
+
+    let mut context =
+        Context::new(&mainloop, osd::APPNAME).ok_or(PulseaudioError::ContextNewError)?;
+
+    trace!("Connecting to a pulseaudio server");
+    context
+        .connect(server, FlagSet::empty(), None)
+        .map_err(PulseaudioError::ContextConnectError)?;
+
+    trace!("Waiting for the context to become ready");
+    loop {
+        iterate(&mut mainloop, heartbeat.period())?;
+        heartbeat.beat();
+        match context.get_state() {
+            pulse::context::State::Ready => {
+                break;
+            }
+            pulse::context::State::Failed
+            | pulse::context::State::Unconnected
+            | pulse::context::State::Terminated => {
+                return Err(PulseaudioError::ContextStateError);
+            }
+            _ => {}
+        }
+    }
+
+    trace!("Subscribing to SINK events");
+    context.subscribe(InterestMaskSet::SINK, |success| {
+        if !success {
+            error!("Failed to subscribe to pulseaudio events");
+        }
+    });
+
+    let introspector = context.introspect();
+    let changed_handler = sink_info_handler(osd.clone(), prev_state.clone(), false);
+
+    let subscribe_callback = move |facility, operation, index| {
+        if facility == Some(Facility::Sink) && operation == Some(Operation::Changed) {
+            trace!("Sink has been changed");
+            introspector.get_sink_info_by_index(index, changed_handler.clone());
+        }
+    };
+
+    context.set_subscribe_callback(Some(Box::new(subscribe_callback)));
+
+    let reload = Rc::new(Cell::new(false));
+    let reload_requested = reload.clone();
+    let introspector = context.introspect();
+    let fd = commands.wakeup.as_raw_fd();
+    let command_callback = move |_, _, _| {
+        let mut buf = [0; 64];
+        while let Ok(n) = (&commands.wakeup).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        for command in commands.receiver.try_iter() {
+            match command {
+                Command::ShowCurrent => {
+                    let handler = sink_info_handler(osd.clone(), prev_state.clone(), true);
+                    introspector.get_sink_info_by_name("@DEFAULT_SINK@", handler);
+                }
+                Command::Reload => reload_requested.set(true),
+            }
+        }
+    };
+    let _command_event = mainloop
+        .new_io_event(fd, IoEventFlagSet::INPUT, Box::new(command_callback))
+        .ok_or(PulseaudioError::ControlEventError)?;
+
+    ready();
+
+    // Run the mainloop by hand to notice when the server goes away
+    loop {
+        iterate(&mut mainloop, heartbeat.period())?;
+        heartbeat.beat();
+        if context.get_state() != State::Ready {
+            return Err(PulseaudioError::ContextStateError);
+        }
+        if reload.get() {
+            return Ok(());
+        }
+    }
+}
+
+/// Run one iteration of the mainloop, waiting for events for at most `timeout` if it is set
+fn iterate(mainloop: &mut Mainloop, timeout: Option<Duration>) -> Result<(), PulseaudioError> {
+    let timeout = timeout.map(|timeout| MicroSeconds(timeout.as_micros() as u64));
+    match mainloop
+        .prepare(timeout)
+        .and_then(|_| mainloop.poll())
+        .and_then(|_| mainloop.dispatch())
+    {
+        Ok(_) => Ok(()),
+        // What the mainloop returns once asked to quit
+        Err(PAErr(-2)) => Err(PulseaudioError::MainloopQuit),
+        Err(paerr) => Err(PulseaudioError::MainloopRunErr(paerr)),
+    }
+}
+
+#[cfg(test)]
+mod osd_tests {
+    use super::*;
+    use osd::notify::Style;
+    use simple_osd_test_support::NotificationServer;
+
+    #[test]
+    fn volume_change_replaces_the_notification() {
+        let server = NotificationServer::start();
+        let mut osd = OSD::builder().style(Style::default()).build();
+        let prev_state = SinkStates::default();
+
+        show_volume(&mut osd, &prev_state, "Speakers", 0.5, false, false);
+        show_volume(&mut osd, &prev_state, "Speakers", 0.5, false, false);
+        show_volume(&mut osd, &prev_state, "Speakers", 0.6, false, false);
+
+        let notifications = server.notifications();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[1].replaces_id, notifications[0].id);
+        assert_eq!(notifications[1].summary, "Volume on Speakers");
+        assert_eq!(notifications[1].icon, "audio-volume-medium");
+    }
+
+    #[test]
+    fn mute_is_shown() {
+        let server = NotificationServer::start();
+        let mut osd = OSD::builder().style(Style::default()).build();
+        let prev_state = SinkStates::default();
+
+        show_volume(&mut osd, &prev_state, "Speakers", 0.5, false, false);
+        show_volume(&mut osd, &prev_state, "Speakers", 0.5, true, false);
+
+        let notifications = server.notifications();
+        assert_eq!(notifications[1].summary, "Volume on Speakers [MUTED]");
+        assert_eq!(notifications[1].icon, "audio-volume-muted");
+    }
+}
@@ -0,0 +1,29 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+extern crate simple_osd_battery as battery;
+extern crate simple_osd_bluetooth as bluetooth;
+extern crate simple_osd_brightness as brightness;
+extern crate simple_osd_common as osd;
+extern crate simple_osd_mpris as mpris;
+extern crate simple_osd_pulseaudio as pulseaudio;
+
+use osd::daemon::{daemon_fn, run_many, DaemonFn};
+use std::fmt::Display;
+
+fn daemon<E: Display + 'static>(f: fn() -> Result<(), E>) -> DaemonFn {
+    Box::new(move || f().map_err(|err| err.to_string()))
+}
+
+fn main() {
+    run_many(
+        "simple-osd",
+        vec![
+            ("battery", daemon(battery::battery_daemon)),
+            ("brightness", daemon_fn::<brightness::Brightness>()),
+            ("mpris", daemon(mpris::daemon_mpris)),
+            ("pulseaudio", daemon(pulseaudio::pulseaudio_daemon)),
+            ("bluetooth", daemon(bluetooth::bluetooth_daemon)),
+        ],
+    );
+}
@@ -0,0 +1,351 @@
+// This is free and unencumbered software released into the public domain.
+// balsoft 2020
+
+//! Integration test harness: a private `dbus-daemon` with a fake `org.freedesktop.Notifications`
+//! server that records every call, so that tests can check which OSDs a daemon has shown.
+//!
+//! ```no_run
+//! use simple_osd_test_support::NotificationServer;
+//!
+//! let server = NotificationServer::start();
+//! // ... show some OSDs ...
+//! assert_eq!(server.notifications().len(), 1);
+//! ```
+//!
+//! `dbus-daemon` has to be installed; the tests fail rather than pass without checking anything.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zbus::blocking::{connection, Connection};
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{OwnedValue, Value};
+
+static PATH: &str = "/org/freedesktop/Notifications";
+
+/// A `Notify` call, with the hints the daemons use picked out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// The id the server has given to the notification
+    pub id: u32,
+    pub replaces_id: u32,
+    pub app_name: String,
+    pub icon: String,
+    pub summary: String,
+    pub body: String,
+    pub expire_timeout: i32,
+    /// 0, 1 and 2 for low, normal and critical
+    pub urgency: Option<u8>,
+    pub category: Option<String>,
+    /// Progress in percent
+    pub value: Option<i32>,
+    /// Buttons as `(identifier, label)`
+    pub actions: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Notify(Notification),
+    CloseNotification(u32),
+}
+
+#[derive(Default)]
+struct Recorder {
+    calls: Mutex<Vec<Call>>,
+    last_id: Mutex<u32>,
+}
+
+struct Server {
+    recorder: Arc<Recorder>,
+}
+
+fn hint<'a, T>(hints: &'a HashMap<String, OwnedValue>, name: &str) -> Option<T>
+where
+    T: TryFrom<&'a Value<'a>>,
+    <T as TryFrom<&'a Value<'a>>>::Error: Into<zbus::zvariant::Error>,
+{
+    hints.get(name).and_then(|value| value.downcast_ref().ok())
+}
+
+#[interface(name = "org.freedesktop.Notifications")]
+impl Server {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: String,
+        replaces_id: u32,
+        app_icon: String,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        expire_timeout: i32,
+    ) -> u32 {
+        let id = if replaces_id != 0 {
+            replaces_id
+        } else {
+            let mut last_id = self.recorder.last_id.lock().unwrap();
+            *last_id += 1;
+            *last_id
+        };
+        self.recorder
+            .calls
+            .lock()
+            .unwrap()
+            .push(Call::Notify(Notification {
+                id,
+                replaces_id,
+                app_name,
+                icon: app_icon,
+                summary,
+                body,
+                expire_timeout,
+                urgency: hint(&hints, "urgency"),
+                category: hint::<&str>(&hints, "category").map(String::from),
+                value: hint(&hints, "value"),
+                actions: actions
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair.get(1).cloned().unwrap_or_default()))
+                    .collect(),
+            }));
+        id
+    }
+
+    async fn close_notification(
+        &self,
+        id: u32,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> zbus::fdo::Result<()> {
+        self.recorder
+            .calls
+            .lock()
+            .unwrap()
+            .push(Call::CloseNotification(id));
+        // Closed by a call to CloseNotification
+        Server::notification_closed(&emitter, id, 3).await?;
+        Ok(())
+    }
+
+    fn get_capabilities(&self) -> Vec<String> {
+        vec![String::from("body"), String::from("actions")]
+    }
+
+    fn get_server_information(&self) -> (String, String, String, String) {
+        (
+            String::from("simple-osd-test-support"),
+            String::from("simple-osd"),
+            String::from(env!("CARGO_PKG_VERSION")),
+            String::from("1.2"),
+        )
+    }
+
+    #[zbus(signal)]
+    async fn action_invoked(
+        emitter: &SignalEmitter<'_>,
+        id: u32,
+        action_key: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn notification_closed(
+        emitter: &SignalEmitter<'_>,
+        id: u32,
+        reason: u32,
+    ) -> zbus::Result<()>;
+}
+
+/// Tests using the session bus can't run in parallel, since it is found through the environment
+static SESSION: Mutex<()> = Mutex::new(());
+
+/// Where the private buses of this process listen, one at a time.
+///
+/// The daemons find the session bus through `DBUS_SESSION_BUS_ADDRESS`, so it is set once, the
+/// first time a bus is started, rather than before and after every test while other test threads
+/// may be reading the environment.
+fn socket() -> &'static PathBuf {
+    static SOCKET: OnceLock<PathBuf> = OnceLock::new();
+    SOCKET.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("simple-osd-test-bus-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Failed to create a directory for the private bus");
+        let socket = dir.join("bus");
+        env::set_var(
+            "DBUS_SESSION_BUS_ADDRESS",
+            format!("unix:path={}", socket.display()),
+        );
+        socket
+    })
+}
+
+/// A private `dbus-daemon`, which is the session bus of this process until it is dropped
+pub struct Bus {
+    process: Child,
+    address: String,
+    _session: MutexGuard<'static, ()>,
+}
+
+impl Bus {
+    /// Start `dbus-daemon`, which has to be installed
+    pub fn start() -> Bus {
+        let session = SESSION.lock().unwrap_or_else(|err| err.into_inner());
+
+        let socket = socket();
+        let address = format!("unix:path={}", socket.display());
+        // Left over by the bus of the previous test
+        let _ = fs::remove_file(socket);
+        let process = match Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--nopidfile"])
+            .arg(format!("--address={}", address))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(process) => process,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                panic!("dbus-daemon is needed to run this test, but it is not installed")
+            }
+            Err(err) => panic!("Failed to start dbus-daemon: {}", err),
+        };
+
+        let bus = Bus {
+            process,
+            address,
+            _session: session,
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while connection::Builder::address(bus.address())
+            .and_then(|builder| builder.build())
+            .is_err()
+        {
+            assert!(
+                Instant::now() < deadline,
+                "dbus-daemon didn't start listening"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        bus
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// A new connection to this bus
+    pub fn connect(&self) -> Connection {
+        connection::Builder::address(self.address())
+            .and_then(|builder| builder.build())
+            .expect("Failed to connect to the private bus")
+    }
+
+    /// Export `iface` at `path` under the well-known `name`, for as long as the returned
+    /// connection lives
+    pub fn serve<I: zbus::object_server::Interface>(
+        &self,
+        name: &str,
+        path: &str,
+        iface: I,
+    ) -> Connection {
+        let connection = self.connect();
+        connection
+            .object_server()
+            .at(path, iface)
+            .expect("Failed to export the mock object");
+        connection
+            .request_name(name)
+            .expect("Failed to acquire the bus name");
+        connection
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// A fake notification server on a private session bus
+pub struct NotificationServer {
+    recorder: Arc<Recorder>,
+    connection: Connection,
+    bus: Bus,
+}
+
+impl NotificationServer {
+    /// Start `dbus-daemon`, which has to be installed, and the server
+    pub fn start() -> NotificationServer {
+        let bus = Bus::start();
+        let recorder = Arc::new(Recorder::default());
+        let connection = bus.serve(
+            "org.freedesktop.Notifications",
+            PATH,
+            Server {
+                recorder: recorder.clone(),
+            },
+        );
+        NotificationServer {
+            recorder,
+            connection,
+            bus,
+        }
+    }
+
+    /// The private bus, to export more mock services on
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    /// Every call so far, oldest first
+    pub fn calls(&self) -> Vec<Call> {
+        self.recorder.calls.lock().unwrap().clone()
+    }
+
+    /// Every `Notify` call so far, oldest first
+    pub fn notifications(&self) -> Vec<Notification> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Notify(notification) => Some(notification),
+                Call::CloseNotification(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.recorder.calls.lock().unwrap().clear();
+    }
+
+    fn server(&self) -> zbus::blocking::object_server::InterfaceRef<Server> {
+        self.connection
+            .object_server()
+            .interface::<_, Server>(PATH)
+            .expect("The notification server is exported")
+    }
+
+    /// Close notification `id` as if the user has dismissed it
+    pub fn dismiss(&self, id: u32) {
+        zbus::block_on(Server::notification_closed(
+            self.server().signal_emitter(),
+            id,
+            2,
+        ))
+        .expect("Failed to emit NotificationClosed");
+    }
+
+    /// Click the button `action` of notification `id`
+    pub fn invoke_action(&self, id: u32, action: &str) {
+        zbus::block_on(Server::action_invoked(
+            self.server().signal_emitter(),
+            id,
+            action,
+        ))
+        .expect("Failed to emit ActionInvoked");
+    }
+}